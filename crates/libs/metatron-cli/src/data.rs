@@ -0,0 +1,227 @@
+use crate::error::CliError;
+use crate::generate::{is_stdio, key_value, read_input};
+use bytes::Bytes;
+use metatron::{
+    CsvOptions, CsvSource, DataPath, NdjsonSource, OutputFormat, Report, ReportError, Template,
+};
+use serde_json::{Map, Value as JValue};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Where the data of a report comes from, shared by `generate` and `watch`.
+#[derive(clap::Args, Debug)]
+pub(crate) struct DataArgs {
+    /// Data file, or `-` for stdin; read as CSV if it ends in `.csv`, as NDJSON if it ends in `.ndjson` or
+    /// `.jsonl`, as JSON otherwise
+    #[arg(short, long, value_name = "PATH")]
+    pub(crate) data: PathBuf,
+
+    /// Read the data as CSV, whatever its extension
+    #[arg(long, conflicts_with = "ndjson")]
+    csv: bool,
+
+    /// Read the data as newline-delimited JSON rows, whatever its extension
+    #[arg(long)]
+    ndjson: bool,
+
+    /// JSON object of params, overriding those of JSON data. Defaults to
+    /// `<data>.params.json` next to the data file, if it exists
+    #[arg(long, value_name = "PATH")]
+    params: Option<PathBuf>,
+
+    /// Sets `key` in the data's `params`; `value` is read as JSON if it is valid JSON, as a string otherwise
+    #[arg(long = "param", value_name = "KEY=VALUE", value_parser = key_value)]
+    overrides: Vec<(String, String)>,
+
+    /// Field separator of CSV data
+    #[arg(long, value_name = "CHAR", default_value = ",", value_parser = ascii_byte)]
+    delimiter: u8,
+
+    /// Quote character of CSV data
+    #[arg(long, value_name = "CHAR", default_value = "\"", value_parser = ascii_byte)]
+    quote: u8,
+
+    /// Text encoding of CSV data, such as `utf-8` or `windows-1252`
+    #[arg(long, value_name = "LABEL", default_value = "utf-8")]
+    encoding: String,
+
+    /// Read CSV fields that are numbers as numbers, and empty fields as null
+    #[arg(long)]
+    infer_types: bool,
+}
+
+/// How the data file is read. CSV and NDJSON rows are streamed into the report.
+enum DataFormat {
+    /// An object with `rows` and `params`.
+    Json,
+    Csv,
+    Ndjson,
+}
+
+impl DataArgs {
+    fn format(&self) -> DataFormat {
+        let extension = self
+            .data
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            _ if self.csv => DataFormat::Csv,
+            _ if self.ndjson => DataFormat::Ndjson,
+            Some("csv") => DataFormat::Csv,
+            Some("ndjson" | "jsonl") => DataFormat::Ndjson,
+            _ => DataFormat::Json,
+        }
+    }
+
+    /// The --params file, or the sidecar `<data>.params.json` if there is one.
+    fn params_path(&self) -> Option<PathBuf> {
+        if self.params.is_some() || is_stdio(&self.data) {
+            return self.params.clone();
+        }
+        let sidecar = self.data.with_extension("params.json");
+        sidecar.is_file().then_some(sidecar)
+    }
+
+    /// Files whose changes change the report.
+    pub(crate) fn paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.data.clone()];
+        paths.extend(self.params.clone());
+        if self.params.is_none() && !is_stdio(&self.data) {
+            paths.push(self.data.with_extension("params.json"));
+        }
+        paths
+    }
+
+    pub(crate) fn generate(
+        &self,
+        template: &str,
+        images: &HashMap<String, Bytes>,
+        format: OutputFormat,
+    ) -> Result<Bytes, CliError> {
+        let params = self.read_params()?;
+        let report = match self.format() {
+            DataFormat::Csv => {
+                let options = CsvOptions {
+                    delimiter: self.delimiter,
+                    quote: self.quote,
+                    encoding: self.encoding.clone(),
+                    infer_types: self.infer_types,
+                };
+                let params = JValue::Object(params).to_string();
+                CsvSource::new(open(&self.data)?, &options).and_then(|mut rows| {
+                    Report::generate_from_source(template, &mut rows, Some(&params), images, format)
+                })
+            }
+            DataFormat::Ndjson => {
+                let mut rows = NdjsonSource::new(open(&self.data)?);
+                let params = JValue::Object(params).to_string();
+                Report::generate_from_source(template, &mut rows, Some(&params), images, format)
+            }
+            DataFormat::Json => {
+                let data = read_input(&self.data)?;
+                let data = if params.is_empty() {
+                    Ok(data)
+                } else {
+                    Template::parse(template).and_then(|template| {
+                        merge_params(&data, params, template.data.params.as_ref())
+                    })
+                };
+                data.and_then(|data| Report::generate(template, &data, images, format))
+            }
+        };
+        report.map_err(|e| CliError::report(&e, template))
+    }
+
+    /// Params of the params file, overridden by those of --param.
+    fn read_params(&self) -> Result<Map<String, JValue>, CliError> {
+        let mut params = match self.params_path() {
+            Some(path) => {
+                let text = read_input(&path)?;
+                match serde_json::from_str(&text) {
+                    Ok(JValue::Object(params)) => params,
+                    Ok(_) => {
+                        return Err(CliError::Usage(format!(
+                            "{}: params must be a JSON object",
+                            path.display()
+                        )))
+                    }
+                    Err(e) => {
+                        return Err(CliError::Usage(format!(
+                            "{}: {}",
+                            path.display(),
+                            ReportError::from(e)
+                        )))
+                    }
+                }
+            }
+            None => Map::new(),
+        };
+        for (key, value) in &self.overrides {
+            let value =
+                serde_json::from_str(value).unwrap_or_else(|_| JValue::String(value.to_string()));
+            params.insert(key.clone(), value);
+        }
+        Ok(params)
+    }
+}
+
+/// Parses a single ASCII character argument.
+fn ascii_byte(arg: &str) -> Result<u8, String> {
+    match arg.as_bytes() {
+        [byte] if byte.is_ascii() => Ok(*byte),
+        _ => Err(format!("expected a single ASCII character, got '{}'", arg)),
+    }
+}
+
+/// Opens `path`, or stdin for `-`, to be read as the report is rendered.
+fn open(path: &Path) -> Result<Box<dyn Read>, CliError> {
+    if is_stdio(path) {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        let file = File::open(path).map_err(|e| CliError::io(path, e))?;
+        Ok(Box::new(file))
+    }
+}
+
+/// Overrides entries of the params object of `data` with `params`: the object
+/// at `path` when the template says where the params are, `params` otherwise.
+fn merge_params(
+    data: &str,
+    params: Map<String, JValue>,
+    path: Option<&DataPath>,
+) -> Result<String, ReportError> {
+    let mut data: JValue = serde_json::from_str(data)?;
+    let target = match path {
+        Some(path) => match path.select_mut(&mut data) {
+            Some(JValue::Object(target)) => target,
+            Some(_) => {
+                return Err(ReportError::InvalidData(format!(
+                    "Params require '{}' to be a JSON object",
+                    path
+                )))
+            }
+            None => return Err(ReportError::InvalidData(format!("No params at '{}'", path))),
+        },
+        None => {
+            let Some(object) = data.as_object_mut() else {
+                return Err(ReportError::InvalidData(
+                    "Params require the data to be a JSON object".to_string(),
+                ));
+            };
+            let entry = object
+                .entry("params")
+                .or_insert_with(|| JValue::Object(Map::new()));
+            let Some(target) = entry.as_object_mut() else {
+                return Err(ReportError::InvalidData(
+                    "Params require 'params' to be a JSON object".to_string(),
+                ));
+            };
+            target
+        }
+    };
+    target.extend(params);
+    Ok(data.to_string())
+}
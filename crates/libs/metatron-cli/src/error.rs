@@ -0,0 +1,50 @@
+use metatron::ReportError;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub(crate) enum CliError {
+    /// Invalid command line arguments.
+    #[error("{0}")]
+    Usage(String),
+
+    #[error("{}: {source}", .path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    /// A report that failed to generate, rendered by [`ReportError::render_diagnostic`].
+    #[error("{0}")]
+    Report(String),
+}
+
+impl CliError {
+    pub(crate) fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        CliError::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// Describes `error` for the author of `template`.
+    pub(crate) fn report(error: &ReportError, template: &str) -> Self {
+        CliError::Report(error.render_diagnostic(template))
+    }
+
+    /// Text to show the user, ending with a newline.
+    pub(crate) fn message(&self) -> String {
+        match self {
+            CliError::Report(diagnostic) => diagnostic.clone(),
+            e => format!("error: {}\n", e),
+        }
+    }
+
+    /// Process exit code: 1 for reports that failed to generate, 2 for invalid
+    /// arguments, as clap uses, and 3 for files that could not be read or written.
+    pub(crate) fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Report(_) => 1,
+            CliError::Usage(_) => 2,
+            CliError::Io { .. } => 3,
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use crate::data::DataArgs;
+use crate::error::CliError;
+use bytes::Bytes;
+use metatron::OutputFormat;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct GenerateArgs {
+    /// Template file, or `-` for stdin
+    #[arg(short, long, value_name = "PATH")]
+    template: PathBuf,
+
+    #[command(flatten)]
+    data: DataArgs,
+
+    /// Output format; inferred from the extension of --out if omitted
+    #[arg(short, long)]
+    format: Option<OutputFormat>,
+
+    /// Report file, or `-` for stdout
+    #[arg(short, long, value_name = "PATH", default_value = "-")]
+    out: PathBuf,
+
+    /// Image the template refers to as `name`, read from `path`
+    #[arg(long = "image", value_name = "NAME=PATH", value_parser = key_value)]
+    images: Vec<(String, String)>,
+}
+
+pub(crate) fn run(args: GenerateArgs) -> Result<(), CliError> {
+    if is_stdio(&args.template) && is_stdio(&args.data.data) {
+        return Err(CliError::Usage(
+            "--template and --data cannot both be read from stdin".to_string(),
+        ));
+    }
+    let format = match args.format {
+        Some(format) => format,
+        None => infer_format(&args.out)?,
+    };
+    let template = read_input(&args.template)?;
+    let images = read_images(&args.images)?;
+
+    let report = args.data.generate(&template, &images, format)?;
+    write_output(&args.out, &report)
+}
+
+/// Parses a `key=value` argument.
+pub(crate) fn key_value(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", arg)),
+    }
+}
+
+pub(crate) fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+fn infer_format(out: &Path) -> Result<OutputFormat, CliError> {
+    let extension = if is_stdio(out) {
+        None
+    } else {
+        out.extension().and_then(|extension| extension.to_str())
+    };
+    let Some(extension) = extension else {
+        return Err(CliError::Usage(
+            "--format is required unless --out has a file extension".to_string(),
+        ));
+    };
+    extension.parse().map_err(|_| {
+        CliError::Usage(format!(
+            "Unknown format of '{}'; pass --format",
+            out.display()
+        ))
+    })
+}
+
+pub(crate) fn read_input(path: &Path) -> Result<String, CliError> {
+    if is_stdio(path) {
+        let mut input = String::new();
+        io::stdin()
+            .read_to_string(&mut input)
+            .map_err(|e| CliError::io("<stdin>", e))?;
+        Ok(input)
+    } else {
+        fs::read_to_string(path).map_err(|e| CliError::io(path, e))
+    }
+}
+
+/// Reads the files of `--image name=path` arguments.
+pub(crate) fn read_images(images: &[(String, String)]) -> Result<HashMap<String, Bytes>, CliError> {
+    let mut map = HashMap::new();
+    for (name, path) in images {
+        let bytes = fs::read(path).map_err(|e| CliError::io(path, e))?;
+        map.insert(name.clone(), Bytes::from(bytes));
+    }
+    Ok(map)
+}
+
+pub(crate) fn write_output(path: &Path, report: &[u8]) -> Result<(), CliError> {
+    if is_stdio(path) {
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(report)
+            .and_then(|_| stdout.flush())
+            .map_err(|e| CliError::io("<stdout>", e))
+    } else {
+        fs::write(path, report).map_err(|e| CliError::io(path, e))
+    }
+}
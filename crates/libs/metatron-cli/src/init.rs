@@ -0,0 +1,31 @@
+use crate::error::CliError;
+use crate::generate::{is_stdio, read_input, write_output};
+use metatron::Report;
+use std::path::PathBuf;
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct InitArgs {
+    /// Sample data file, or `-` for stdin
+    #[arg(short, long, value_name = "PATH")]
+    data: PathBuf,
+
+    /// Template file to write, or `-` for stdout
+    #[arg(short, long, value_name = "PATH", default_value = "-")]
+    out: PathBuf,
+
+    /// Overwrite --out if it exists
+    #[arg(long)]
+    force: bool,
+}
+
+pub(crate) fn run(args: InitArgs) -> Result<(), CliError> {
+    if !is_stdio(&args.out) && !args.force && args.out.exists() {
+        return Err(CliError::Usage(format!(
+            "{} already exists; pass --force to overwrite it",
+            args.out.display()
+        )));
+    }
+    let data = read_input(&args.data)?;
+    let template = Report::scaffold(&data).map_err(|e| CliError::report(&e, ""))?;
+    write_output(&args.out, template.as_bytes())
+}
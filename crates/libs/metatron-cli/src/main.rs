@@ -1,3 +1,62 @@
-fn main() {
-    println!("Hello, world!");
+use clap::{Parser, Subcommand};
+use metatron::Report;
+use std::process::ExitCode;
+
+mod data;
+mod error;
+mod generate;
+mod init;
+mod watch;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "metatron",
+    author,
+    version,
+    about = "Metatron: Implementation in Rust of a report generation based on Shiva library",
+    long_about = None
+)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the supported output formats
+    Formats,
+    /// Generate a report from a template and data
+    Generate(generate::GenerateArgs),
+    /// Write a starting template for sample data
+    Init(init::InitArgs),
+    /// Serve an HTML preview of a report, re-rendered whenever its files change
+    Watch(watch::WatchArgs),
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let result = match args.command {
+        Command::Formats => {
+            for format in Report::supported_formats() {
+                println!(
+                    "{}\t.{}\t{}",
+                    format,
+                    format.extension(),
+                    format.mime_type()
+                );
+            }
+            Ok(())
+        }
+        Command::Generate(args) => generate::run(args),
+        Command::Init(args) => init::run(args),
+        Command::Watch(args) => watch::run(args),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprint!("{}", e.message());
+            ExitCode::from(e.exit_code())
+        }
+    }
 }
@@ -0,0 +1,139 @@
+use crate::data::DataArgs;
+use crate::error::CliError;
+use crate::generate::{is_stdio, key_value, read_images, read_input};
+use axum::extract::State;
+use axum::response::Html;
+use axum::routing::get;
+use axum::Router;
+use metatron::OutputFormat;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often the watched files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct WatchArgs {
+    /// Template file
+    #[arg(short, long, value_name = "PATH")]
+    template: PathBuf,
+
+    #[command(flatten)]
+    data: DataArgs,
+
+    /// Image the template refers to as `name`, read from `path`
+    #[arg(long = "image", value_name = "NAME=PATH", value_parser = key_value)]
+    images: Vec<(String, String)>,
+
+    /// Port of the preview on localhost
+    #[arg(short, long, default_value_t = 8080)]
+    port: u16,
+}
+
+/// The latest rendering; `version` changes with every re-render.
+#[derive(Default)]
+struct Preview {
+    version: u64,
+    html: String,
+}
+
+type SharedPreview = Arc<Mutex<Preview>>;
+
+pub(crate) fn run(args: WatchArgs) -> Result<(), CliError> {
+    if is_stdio(&args.template) || is_stdio(&args.data.data) {
+        return Err(CliError::Usage(
+            "watch reads --template and --data from files, not stdin".to_string(),
+        ));
+    }
+    let address = format!("127.0.0.1:{}", args.port);
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start the async runtime");
+    let listener = runtime
+        .block_on(tokio::net::TcpListener::bind(&address))
+        .map_err(|e| CliError::io(&address, e))?;
+
+    let preview = SharedPreview::default();
+    let watched = preview.clone();
+    thread::spawn(move || watch(&args, &watched));
+
+    println!("Previewing at http://{}", address);
+    let router = Router::new()
+        .route("/", get(page))
+        .route("/version", get(version))
+        .with_state(preview);
+    runtime
+        .block_on(async { axum::serve(listener, router).await })
+        .map_err(|e| CliError::io(&address, e))
+}
+
+/// Re-renders the preview whenever the template, the data, its params or an image changes.
+fn watch(args: &WatchArgs, preview: &Mutex<Preview>) {
+    let mut paths = vec![args.template.clone()];
+    paths.extend(args.data.paths());
+    paths.extend(args.images.iter().map(|(_, path)| PathBuf::from(path)));
+    let mut last_modified = None;
+    loop {
+        let modified: Vec<Option<(SystemTime, u64)>> = paths
+            .iter()
+            .map(|path| {
+                let metadata = fs::metadata(path).ok()?;
+                Some((metadata.modified().ok()?, metadata.len()))
+            })
+            .collect();
+        if last_modified.as_ref() != Some(&modified) {
+            last_modified = Some(modified);
+            let html = match render(args) {
+                Ok(html) => {
+                    println!("Rendered {}", args.template.display());
+                    html
+                }
+                Err(e) => {
+                    eprint!("{}", e.message());
+                    error_page(&e.message())
+                }
+            };
+            let mut preview = preview.lock().unwrap();
+            preview.version += 1;
+            preview.html = html;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn render(args: &WatchArgs) -> Result<String, CliError> {
+    let template = read_input(&args.template)?;
+    let images = read_images(&args.images)?;
+    let report = args.data.generate(&template, &images, OutputFormat::Html)?;
+    Ok(String::from_utf8_lossy(&report).into_owned())
+}
+
+fn error_page(message: &str) -> String {
+    let message = message
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Report error</title>\n</head>\n<body>\n<pre style=\"color: #b00020\">{}</pre>\n</body>\n</html>\n",
+        message
+    )
+}
+
+/// The rendered report, with a script reloading it once the version changes.
+async fn page(State(preview): State<SharedPreview>) -> Html<String> {
+    let preview = preview.lock().unwrap();
+    let script = format!(
+        "<script>\nsetInterval(async () => {{\n  try {{\n    const response = await fetch(\"/version\");\n    if (await response.text() !== \"{}\") location.reload();\n  }} catch (e) {{}}\n}}, 500);\n</script>\n",
+        preview.version
+    );
+    let html = match preview.html.rfind("</body>") {
+        Some(end) => format!("{}{}{}", &preview.html[..end], script, &preview.html[end..]),
+        None => format!("{}{}", preview.html, script),
+    };
+    Html(html)
+}
+
+async fn version(State(preview): State<SharedPreview>) -> String {
+    preview.lock().unwrap().version.to_string()
+}
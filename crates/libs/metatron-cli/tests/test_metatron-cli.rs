@@ -0,0 +1,378 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+const TEMPLATE: &str = "../../../data/report-template.kdl";
+const DATA: &str = "../../../data/report-data.json";
+const LOGO: &str = "../../../logo.png";
+
+fn metatron(args: &[&str], stdin: Option<&str>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_metatron"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to run metatron");
+    let mut input = child.stdin.take().unwrap();
+    input
+        .write_all(stdin.unwrap_or_default().as_bytes())
+        .unwrap();
+    drop(input);
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_generate() {
+    let image = format!("{}={}", LOGO, LOGO);
+    let output = metatron(
+        &[
+            "generate",
+            "--template",
+            TEMPLATE,
+            "--data",
+            DATA,
+            "--format",
+            "html",
+            "--image",
+            &image,
+            "--param",
+            "company_name=XYZ Corp",
+        ],
+        None,
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let html = String::from_utf8(output.stdout).unwrap();
+    assert!(html.contains("XYZ Corp Employee Report"));
+
+    let out = std::env::temp_dir().join(format!("metatron-cli-{}.html", std::process::id()));
+    let template = std::fs::read_to_string(TEMPLATE).expect("Failed to read file");
+    let output = metatron(
+        &[
+            "generate",
+            "--template",
+            "-",
+            "--data",
+            DATA,
+            "--out",
+            out.to_str().unwrap(),
+            "--image",
+            &image,
+        ],
+        Some(&template),
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let html = std::fs::read_to_string(&out).unwrap();
+    assert!(html.contains("ABCDFG Ltd Employee Report"));
+    std::fs::remove_file(out).unwrap();
+}
+
+#[test]
+fn test_generate_errors() {
+    let template = std::fs::read_to_string(TEMPLATE)
+        .expect("Failed to read file")
+        .replace("header level=1", "header");
+    let output = metatron(
+        &[
+            "generate",
+            "--template",
+            "-",
+            "--data",
+            DATA,
+            "--format",
+            "text",
+        ],
+        Some(&template),
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("error: Missing 'level'\n --> line 6, column 9"));
+
+    let output = metatron(
+        &[
+            "generate",
+            "--template",
+            TEMPLATE,
+            "--data",
+            DATA,
+            "--out",
+            "report",
+        ],
+        None,
+    );
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = metatron(
+        &[
+            "generate",
+            "--template",
+            "missing.kdl",
+            "--data",
+            DATA,
+            "--format",
+            "pdf",
+        ],
+        None,
+    );
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("error: missing.kdl: "));
+
+    let output = metatron(
+        &[
+            "generate",
+            "--template",
+            TEMPLATE,
+            "--data",
+            "-",
+            "--param",
+            "year=2024",
+            "--format",
+            "text",
+        ],
+        Some("[]"),
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Invalid data: Params require the data to be a JSON object"));
+}
+
+/// Fetches `path` from the preview server on `port`.
+fn get(port: u16, path: &str) -> Option<String> {
+    use std::io::Read;
+    let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).ok()?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    )
+    .ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_string())
+}
+
+fn wait_for(port: u16, expected: &str) -> String {
+    for _ in 0..100 {
+        if let Some(page) = get(port, "/").filter(|page| page.contains(expected)) {
+            return page;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    panic!("Preview never showed '{}'", expected);
+}
+
+/// Stops the preview server even if the test fails.
+struct KillOnDrop(std::process::Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+#[test]
+fn test_watch() {
+    let dir = std::env::temp_dir().join(format!("metatron-watch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let template_path = dir.join("report-template.kdl");
+    let template = std::fs::read_to_string(TEMPLATE).expect("Failed to read file");
+    std::fs::write(&template_path, &template).unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let image = format!("{}={}", LOGO, LOGO);
+
+    let child = Command::new(env!("CARGO_BIN_EXE_metatron"))
+        .args([
+            "watch",
+            "--template",
+            template_path.to_str().unwrap(),
+            "--data",
+            DATA,
+            "--image",
+            &image,
+            "--port",
+            &port.to_string(),
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to run metatron");
+    let child = KillOnDrop(child);
+
+    let page = wait_for(port, "ABCDFG Ltd Employee Report");
+    assert!(page.contains("fetch(\"/version\")"));
+    let version = get(port, "/version").unwrap();
+
+    std::fs::write(&template_path, template.replace("header level=1", "header")).unwrap();
+    let page = wait_for(port, "error: Missing 'level'");
+    assert!(page.contains("line 6, column 9"));
+    assert_ne!(get(port, "/version").unwrap(), version);
+
+    std::fs::write(&template_path, template.replace("Employee", "Staff")).unwrap();
+    wait_for(port, "ABCDFG Ltd Staff Report");
+
+    drop(child);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_init() {
+    let data = std::fs::read_to_string(DATA).expect("Failed to read file");
+    let output = metatron(&["init", "--data", "-"], Some(&data));
+    assert!(output.status.success(), "{:?}", output);
+    let template = String::from_utf8(output.stdout).unwrap();
+    assert!(template.contains("column name=\"Salary\" width=8"));
+
+    let output = metatron(&["init", "--data", DATA, "--out", TEMPLATE], None);
+    assert_eq!(output.status.code(), Some(2));
+    let output = metatron(&["init", "--data", "-"], Some("{\"rows\": [}"));
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("error: JSON parse error"));
+}
+
+#[test]
+fn test_generate_csv() {
+    let dir = std::env::temp_dir().join(format!("metatron-cli-csv-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let data = dir.join("employees.csv");
+    std::fs::write(
+        &data,
+        "name;age;salary\nJohn;25;50000\nJane;30;60000\nJim;35;70000\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("employees.params.json"),
+        r#"{"company_name": "CSV Corp", "company_address": "1 Main St"}"#,
+    )
+    .unwrap();
+    let image = format!("{}={}", LOGO, LOGO);
+    let args = [
+        "generate",
+        "--template",
+        TEMPLATE,
+        "--data",
+        data.to_str().unwrap(),
+        "--format",
+        "html",
+        "--image",
+        &image,
+        "--delimiter",
+        ";",
+        "--infer-types",
+    ];
+
+    let output = metatron(&args, None);
+    assert!(output.status.success(), "{:?}", output);
+    let html = String::from_utf8(output.stdout).unwrap();
+    assert!(html.contains("CSV Corp Employee Report"));
+    assert!(html.contains("1 Main St"));
+    assert!(html.contains("Jane"));
+    assert!(html.contains("60000"));
+
+    let mut overridden = args.to_vec();
+    overridden.extend(["--param", "company_name=Override Inc"]);
+    let output = metatron(&overridden, None);
+    assert!(output.status.success(), "{:?}", output);
+    let html = String::from_utf8(output.stdout).unwrap();
+    assert!(html.contains("Override Inc Employee Report"));
+
+    let output = metatron(&[&args[..11], &["--delimiter", "ab"]].concat(), None);
+    assert_eq!(output.status.code(), Some(2));
+
+    let ndjson = dir.join("employees.jsonl");
+    std::fs::write(
+        &ndjson,
+        "{\"name\": \"Jane\", \"age\": 30, \"salary\": 60000}\n{\"name\": \"Jim\", \"age\": 35, \"salary\": 70000}\n",
+    )
+    .unwrap();
+    let params = dir.join("employees.params.json");
+    let output = metatron(
+        &[
+            "generate",
+            "--template",
+            TEMPLATE,
+            "--data",
+            ndjson.to_str().unwrap(),
+            "--params",
+            params.to_str().unwrap(),
+            "--format",
+            "html",
+            "--image",
+            &image,
+        ],
+        None,
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let html = String::from_utf8(output.stdout).unwrap();
+    assert!(html.contains("CSV Corp Employee Report"));
+    assert!(html.contains("65000"));
+
+    let ragged = dir.join("ragged.csv");
+    std::fs::write(&ragged, "name,age\nJohn\n").unwrap();
+    let output = metatron(
+        &[
+            "generate",
+            "--template",
+            TEMPLATE,
+            "--data",
+            ragged.to_str().unwrap(),
+            "--format",
+            "html",
+        ],
+        None,
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("error: CSV parse error"), "{}", stderr);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_generate_data_layout() {
+    let dir = std::env::temp_dir().join(format!("metatron-cli-layout-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let data = dir.join("data.json");
+    std::fs::write(
+        &data,
+        r#"{"items": [{"name": "John"}], "meta": {"company": "A", "city": "Oslo"}}"#,
+    )
+    .unwrap();
+    let template = r#"
+template {
+    data rows="/items" params="/meta"
+    title {
+        header level=1 "Co: $P{company} in $P{city}"
+    }
+}
+"#;
+    let args = [
+        "generate",
+        "--template",
+        "-",
+        "--data",
+        data.to_str().unwrap(),
+        "--format",
+        "text",
+    ];
+
+    let overridden = [&args[..], &["--param", "company=OVERRIDE"]].concat();
+    let output = metatron(&overridden, Some(template));
+    assert!(output.status.success(), "{:?}", output);
+    let text = String::from_utf8(output.stdout).unwrap();
+    assert!(text.contains("Co: OVERRIDE in Oslo"), "{}", text);
+
+    let missing = template.replace("/meta", "/nothing");
+    let output = metatron(&overridden, Some(&missing));
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("No params at '/nothing'"), "{}", stderr);
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,466 @@
+//! Report data: the rows of the table, bound to `$F(...)`, and the params,
+//! bound to `$P{...}`.
+
+use crate::error::ReportError::{self, *};
+use crate::path::DataPath;
+use crate::template::DataLayout;
+use encoding_rs::{CoderResult, Decoder, Encoding};
+use serde_json::{Map, Value as JValue};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read};
+
+/// How to read CSV data. The first record is the header row, naming the
+/// fields of the following ones.
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    /// Field separator, `,` by default.
+    pub delimiter: u8,
+    /// Quote character, `"` by default. Quotes within quoted fields are doubled.
+    pub quote: u8,
+    /// Label of the text encoding, such as `utf-8`, `windows-1252` or `utf-16le`.
+    /// A byte order mark takes precedence.
+    pub encoding: String,
+    /// Whether fields that are JSON numbers become numbers, and empty fields
+    /// nulls. Otherwise every field is a string.
+    pub infer_types: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: b',',
+            quote: b'"',
+            encoding: "utf-8".to_string(),
+            infer_types: false,
+        }
+    }
+}
+
+/// A stream of data rows, read one at a time as they are rendered, so that
+/// the raw data is never parsed as a whole. The rendered table still holds
+/// every row until the document is built.
+///
+/// Any iterator of rows is a source, such as `rows.into_iter().map(Ok)`;
+/// [`JsonArraySource`], [`NdjsonSource`] and [`CsvSource`] read them from files.
+pub trait DataSource: Iterator<Item = Result<JValue, ReportError>> {}
+
+impl<I: Iterator<Item = Result<JValue, ReportError>>> DataSource for I {}
+
+/// Rows of a JSON array, `[{"name": "John"}, ...]`, parsed one element at a time.
+pub struct JsonArraySource<R> {
+    reader: BufReader<R>,
+    /// Rows read so far.
+    count: usize,
+    started: bool,
+    finished: bool,
+    element: Vec<u8>,
+}
+
+impl<R: Read> JsonArraySource<R> {
+    pub fn new(reader: R) -> Self {
+        JsonArraySource {
+            reader: BufReader::new(reader),
+            count: 0,
+            started: false,
+            finished: false,
+            element: Vec::new(),
+        }
+    }
+
+    /// Skips JSON whitespace and returns the next byte, without consuming it.
+    fn peek(&mut self) -> Result<Option<u8>, ReportError> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            match buf.iter().position(|b| !is_whitespace(*b)) {
+                Some(index) => {
+                    let byte = buf[index];
+                    self.reader.consume(index);
+                    return Ok(Some(byte));
+                }
+                None => {
+                    let len = buf.len();
+                    self.reader.consume(len);
+                }
+            }
+        }
+    }
+
+    fn next_element(&mut self) -> Result<Option<JValue>, ReportError> {
+        if !self.started {
+            self.started = true;
+            if self.peek()? != Some(b'[') {
+                return Err(not_an_array());
+            }
+            self.reader.consume(1);
+        }
+        match self.peek()? {
+            Some(b']') => return Ok(None),
+            Some(b',') if self.count > 0 => {
+                self.reader.consume(1);
+                self.peek()?;
+            }
+            _ if self.count > 0 => return Err(not_an_array()),
+            _ => {}
+        }
+
+        // Copy the element up to its end, a buffer at a time, tracking nesting
+        // and strings so that only its own closing bracket or quote, or a
+        // top-level `,`, `]` or whitespace ends it.
+        self.element.clear();
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let mut end = None;
+            for (index, &b) in buf.iter().enumerate() {
+                if in_string {
+                    if escaped {
+                        escaped = false;
+                    } else if b == b'\\' {
+                        escaped = true;
+                    } else if b == b'"' {
+                        in_string = false;
+                        if depth == 0 {
+                            end = Some(index + 1);
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                match b {
+                    b'"' => in_string = true,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' if depth > 0 => {
+                        depth -= 1;
+                        if depth == 0 {
+                            end = Some(index + 1);
+                            break;
+                        }
+                    }
+                    b',' | b'}' | b']' if depth == 0 => {
+                        end = Some(index);
+                        break;
+                    }
+                    b if depth == 0 && is_whitespace(b) => {
+                        end = Some(index);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            let len = end.unwrap_or(buf.len());
+            self.element.extend_from_slice(&buf[..len]);
+            self.reader.consume(len);
+            if end.is_some() {
+                break;
+            }
+        }
+        let row = serde_json::from_slice(&self.element)?;
+        self.count += 1;
+        Ok(Some(row))
+    }
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+fn not_an_array() -> ReportError {
+    InvalidData("Rows must be a JSON array".to_string())
+}
+
+impl<R: Read> Iterator for JsonArraySource<R> {
+    type Item = Result<JValue, ReportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let row = self.next_element().transpose();
+        self.finished = !matches!(row, Some(Ok(_)));
+        row
+    }
+}
+
+/// Rows of newline-delimited JSON, one value per line. Blank lines are skipped.
+pub struct NdjsonSource<R> {
+    reader: BufReader<R>,
+    line: String,
+    finished: bool,
+}
+
+impl<R: Read> NdjsonSource<R> {
+    pub fn new(reader: R) -> Self {
+        NdjsonSource {
+            reader: BufReader::new(reader),
+            line: String::new(),
+            finished: false,
+        }
+    }
+
+    fn next_line(&mut self) -> Result<Option<JValue>, ReportError> {
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            if !self.line.trim().is_empty() {
+                return Ok(Some(serde_json::from_str(&self.line)?));
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for NdjsonSource<R> {
+    type Item = Result<JValue, ReportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let row = self.next_line().transpose();
+        self.finished = !matches!(row, Some(Ok(_)));
+        row
+    }
+}
+
+/// Rows of CSV data, decoded and parsed one record at a time.
+pub struct CsvSource<R> {
+    records: csv::StringRecordsIntoIter<Utf8Reader<R>>,
+    headers: csv::StringRecord,
+    infer_types: bool,
+}
+
+impl<R: Read> CsvSource<R> {
+    /// Reads the header row of `reader`.
+    pub fn new(reader: R, options: &CsvOptions) -> Result<Self, ReportError> {
+        let encoding = Encoding::for_label(options.encoding.as_bytes())
+            .ok_or_else(|| InvalidOption(format!("Unknown encoding: {}", options.encoding)))?;
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(options.delimiter)
+            .quote(options.quote)
+            .from_reader(Utf8Reader::new(reader, encoding));
+        let headers = reader.headers()?.clone();
+        Ok(CsvSource {
+            records: reader.into_records(),
+            headers,
+            infer_types: options.infer_types,
+        })
+    }
+}
+
+impl<R: Read> Iterator for CsvSource<R> {
+    type Item = Result<JValue, ReportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.records.next()? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let row: Map<String, JValue> = self
+            .headers
+            .iter()
+            .zip(record.iter())
+            .map(|(field, value)| {
+                let value = if self.infer_types {
+                    infer_type(value)
+                } else {
+                    JValue::String(value.to_string())
+                };
+                (field.to_string(), value)
+            })
+            .collect();
+        Some(Ok(JValue::Object(row)))
+    }
+}
+
+/// Decodes text in any encoding to UTF-8 as it is read. A byte order mark
+/// overrides the encoding.
+struct Utf8Reader<R> {
+    reader: R,
+    decoder: Decoder,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// Bytes of `output` already returned.
+    position: usize,
+    finished: bool,
+}
+
+/// Bytes read from the underlying reader at a time.
+const CHUNK_SIZE: usize = 8192;
+
+impl<R: Read> Utf8Reader<R> {
+    fn new(reader: R, encoding: &'static Encoding) -> Self {
+        Utf8Reader {
+            reader,
+            decoder: encoding.new_decoder(),
+            input: vec![0; CHUNK_SIZE],
+            output: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+}
+
+impl<R: Read> Read for Utf8Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.output.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            let read = self.reader.read(&mut self.input)?;
+            self.finished = read == 0;
+            let capacity = self
+                .decoder
+                .max_utf8_buffer_length(read)
+                .expect("Chunks are small enough to decode");
+            self.output.resize(capacity, 0);
+            let (result, _, written, malformed) =
+                self.decoder
+                    .decode_to_utf8(&self.input[..read], &mut self.output, self.finished);
+            debug_assert_eq!(result, CoderResult::InputEmpty);
+            if malformed {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Data is not valid {}", self.decoder.encoding().name()),
+                ));
+            }
+            self.output.truncate(written);
+            self.position = 0;
+        }
+        let len = buf.len().min(self.output.len() - self.position);
+        buf[..len].copy_from_slice(&self.output[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+pub(crate) struct Data {
+    pub(crate) rows: Vec<JValue>,
+    pub(crate) params: HashMap<String, JValue>,
+    pub(crate) datasets: HashMap<String, Vec<JValue>>,
+}
+
+impl Data {
+    /// Reads JSON data, with the rows, params and datasets where `layout`
+    /// says they are.
+    pub(crate) fn from_json(data: &str, layout: &DataLayout) -> Result<Data, ReportError> {
+        let mut data: JValue = serde_json::from_str(data)?;
+        let params = match &layout.params {
+            Some(path) => match path.select(&data).as_slice() {
+                [JValue::Object(params)] => (*params).clone(),
+                [] => return Err(InvalidData(format!("No params at '{}'", path))),
+                _ => {
+                    return Err(InvalidData(format!(
+                        "Params at '{}' must be a JSON object",
+                        path
+                    )))
+                }
+            },
+            None => data["params"]
+                .as_object()
+                .ok_or(InvalidData("Missing 'params' in data".to_string()))?
+                .clone(),
+        };
+        // Rows are moved out of the data rather than copied, unless another
+        // path may select some of the same values.
+        let default_rows = DataPath::Pointer("/rows".to_string());
+        let paths: Vec<&DataPath> = layout
+            .datasets
+            .iter()
+            .map(|dataset| &dataset.rows)
+            .chain(Some(layout.rows.as_ref().unwrap_or(&default_rows)))
+            .collect();
+        let take = |path: &DataPath| {
+            !paths
+                .iter()
+                .any(|other| !std::ptr::eq(*other, path) && overlaps(path, other))
+        };
+        let datasets = layout
+            .datasets
+            .iter()
+            .map(|dataset| {
+                let rows = rows_at(&mut data, &dataset.rows, take(&dataset.rows))?;
+                Ok((dataset.name.clone(), rows))
+            })
+            .collect::<Result<_, ReportError>>()?;
+        let rows = match &layout.rows {
+            Some(path) => rows_at(&mut data, path, take(path))?,
+            None => match data.get_mut("rows").map(JValue::take) {
+                Some(JValue::Array(rows)) => rows,
+                None | Some(JValue::Null) => Vec::new(),
+                Some(_) => return Err(not_an_array()),
+            },
+        };
+        Ok(Data {
+            rows,
+            params: params.into_iter().collect(),
+            datasets,
+        })
+    }
+}
+
+/// The rows at `path`: the elements of the array it selects, or each value
+/// it selects if that is not a single array. With `take`, the array a JSON
+/// Pointer selects is moved out of `data` instead of copied.
+fn rows_at(data: &mut JValue, path: &DataPath, take: bool) -> Result<Vec<JValue>, ReportError> {
+    if let (DataPath::Pointer(pointer), true) = (path, take) {
+        return match data.pointer_mut(pointer).map(JValue::take) {
+            Some(JValue::Array(rows)) => Ok(rows),
+            Some(_) => Err(InvalidData(format!("Rows at '{}' must be an array", path))),
+            None => Err(InvalidData(format!("No rows at '{}'", path))),
+        };
+    }
+    match path.select(data).as_slice() {
+        [] => Err(InvalidData(format!("No rows at '{}'", path))),
+        [JValue::Array(rows)] => Ok(rows.clone()),
+        [_] if matches!(path, DataPath::Pointer(_)) => {
+            Err(InvalidData(format!("Rows at '{}' must be an array", path)))
+        }
+        values => Ok(values.iter().map(|value| (*value).clone()).collect()),
+    }
+}
+
+/// Whether `a` and `b` may select some of the same values: JSON Pointers when
+/// one is a prefix of the other, JSONPaths always.
+fn overlaps(a: &DataPath, b: &DataPath) -> bool {
+    let contains = |outer: &str, inner: &str| {
+        inner
+            .strip_prefix(outer)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    match (a, b) {
+        (DataPath::Pointer(a), DataPath::Pointer(b)) => contains(a, b) || contains(b, a),
+        _ => true,
+    }
+}
+
+/// Reads params given apart from the rows, a JSON object.
+pub(crate) fn parse_params(params: Option<&str>) -> Result<HashMap<String, JValue>, ReportError> {
+    match params {
+        Some(params) => match serde_json::from_str(params)? {
+            JValue::Object(params) => Ok(params.into_iter().collect()),
+            _ => Err(InvalidData("Params must be a JSON object".to_string())),
+        },
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// `42` and `-1.5e3` become numbers and an empty field null; anything else,
+/// including `007` and `+1`, stays a string.
+fn infer_type(value: &str) -> JValue {
+    if value.is_empty() {
+        return JValue::Null;
+    }
+    match serde_json::from_str(value) {
+        Ok(number @ JValue::Number(_)) if value.trim() == value => number,
+        _ => JValue::String(value.to_string()),
+    }
+}
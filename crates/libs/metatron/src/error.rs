@@ -1,3 +1,4 @@
+use std::fmt;
 use thiserror::Error;
 
 /// Error type for the `metatron-core` crate.
@@ -15,9 +16,191 @@ pub enum ReportError {
     #[error("JSON parse error: {0}")]
     JsonParseError(#[from] serde_json::Error),
 
+    #[error("CSV parse error: {0}")]
+    CsvParseError(#[from] csv::Error),
+
+    #[error("Data source error: {0}")]
+    DataSourceError(String),
+
+    /// Well-formed data that does not have the shape a report needs.
+    #[error("Invalid data: {0}")]
+    InvalidData(String),
+
+    /// An option for reading the data, such as a CSV encoding, that is not supported.
+    #[error("Invalid option: {0}")]
+    InvalidOption(String),
+
     #[error("I/O error: {0}")]
     IoError(#[from] std::io::Error),
 
+    #[error("Template error at {location}: {message}")]
+    InvalidTemplate {
+        message: String,
+        location: SourceLocation,
+    },
+
+    #[error("Expression error at {location}: {message}")]
+    ExpressionParseError {
+        message: String,
+        location: SourceLocation,
+    },
+
+    #[error("Expression evaluation error: {0}")]
+    ExpressionEvalError(String),
+
+    #[error("Missing field '{field}' in data row {row}")]
+    MissingField { row: usize, field: String },
+
+    #[error("Error in data row {row}: {source}")]
+    DataRow {
+        row: usize,
+        source: Box<ReportError>,
+    },
+
+    #[error("Image not found: {0}")]
+    ImageNotFound(String),
+
+    #[error("Access to resource denied: {0}")]
+    ResourceAccessDenied(String),
+
     #[error("Float parse error: {0}")]
     ParseFloatError(#[from] std::num::ParseFloatError),
 }
+
+/// Where in the template source an error was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// Names of the enclosing KDL nodes, outermost first, e.g. `["template", "title", "header"]`.
+    pub path: Vec<String>,
+    /// Byte offset and length of the offending node or entry.
+    pub offset: usize,
+    pub len: usize,
+    /// 1-based line and column of `offset`.
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourceLocation {
+    pub(crate) fn new(source: &str, offset: usize, len: usize, path: Vec<String>) -> Self {
+        let before = source.get(..offset).unwrap_or(source);
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+        SourceLocation {
+            path,
+            offset,
+            len,
+            line,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)?;
+        if !self.path.is_empty() {
+            write!(f, " ({})", self.path.join(" > "))?;
+        }
+        Ok(())
+    }
+}
+
+impl ReportError {
+    /// Stable, machine-readable name of the kind of error, e.g. `template_parse_error`.
+    /// Errors in a data row have the code of their cause.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ReportError::Common(_) => "internal_error",
+            ReportError::InvalidDocumentType(_) => "invalid_format",
+            ReportError::KdlParseError(_) => "template_parse_error",
+            ReportError::JsonParseError(_)
+            | ReportError::CsvParseError(_)
+            | ReportError::ParseFloatError(_) => "data_parse_error",
+            ReportError::DataSourceError(_) => "data_source_error",
+            ReportError::InvalidData(_) => "data_error",
+            ReportError::InvalidOption(_) => "invalid_option",
+            ReportError::IoError(_) => "io_error",
+            ReportError::InvalidTemplate { .. } => "invalid_template",
+            ReportError::ExpressionParseError { .. } => "expression_parse_error",
+            ReportError::ExpressionEvalError(_) => "expression_eval_error",
+            ReportError::MissingField { .. } => "missing_field",
+            ReportError::DataRow { source, .. } => source.code(),
+            ReportError::ImageNotFound(_) => "missing_resource",
+            ReportError::ResourceAccessDenied(_) => "resource_access_denied",
+        }
+    }
+
+    /// Returns where in the template the error was found, if it relates to the template source.
+    pub fn location(&self) -> Option<SourceLocation> {
+        match self {
+            ReportError::InvalidTemplate { location, .. }
+            | ReportError::ExpressionParseError { location, .. } => Some(location.clone()),
+            ReportError::KdlParseError(error) => Some(SourceLocation::new(
+                &error.input,
+                error.span.offset(),
+                error.span.len(),
+                Vec::new(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Renders the error for template authors, quoting and underlining the
+    /// offending part of `template` when the error has a location:
+    ///
+    /// ```text
+    /// error: Missing 'level'
+    ///  --> line 5, column 9 (template > title > header)
+    ///   |
+    /// 5 |         header "Staff"
+    ///   |         ^^^^^^^^^^^^^^
+    /// ```
+    pub fn render_diagnostic(&self, template: &str) -> String {
+        let message = match self {
+            ReportError::InvalidTemplate { message, .. }
+            | ReportError::ExpressionParseError { message, .. } => message.clone(),
+            ReportError::KdlParseError(error) => match error.label {
+                Some(label) => format!("{}: {}", error, label),
+                None => error.to_string(),
+            },
+            error => error.to_string(),
+        };
+        let mut output = format!("error: {}\n", message);
+        let Some(location) = self.location() else {
+            return output;
+        };
+        let Some(line) = template.lines().nth(location.line - 1) else {
+            output.push_str(&format!(" --> {}\n", location));
+            return output;
+        };
+        let gutter = location.line.to_string().len();
+        let indent: String = line
+            .chars()
+            .take(location.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let start = location.offset.min(template.len());
+        let end = (location.offset + location.len).min(template.len());
+        let width = template
+            .get(start..end)
+            .and_then(|span| span.lines().next())
+            .map(|span| span.chars().count())
+            .unwrap_or(0)
+            .max(1);
+        output.push_str(&format!("{:gutter$}--> {}\n", "", location));
+        output.push_str(&format!("{:gutter$} |\n", ""));
+        output.push_str(&format!("{} | {}\n", location.line, line));
+        output.push_str(&format!(
+            "{:gutter$} | {}{}\n",
+            "",
+            indent,
+            "^".repeat(width)
+        ));
+        if let ReportError::KdlParseError(error) = self {
+            if let Some(help) = error.help {
+                output.push_str(&format!("{:gutter$} = help: {}\n", "", help));
+            }
+        }
+        output
+    }
+}
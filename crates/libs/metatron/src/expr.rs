@@ -0,0 +1,634 @@
+//! Expression language used inside `${ ... }` placeholders.
+//!
+//! An expression can reference fields (`$F(name)`), params (`$P{name}`) and
+//! variables (`$V{name}`), and combine them with arithmetic (`+ - * / %`),
+//! comparison (`== != < <= > >=`), logical (`&& || !`) operators and function
+//! calls such as `upper($F(name))` or `if($F(age) > 30, 'senior', 'junior')`.
+//! `+` concatenates when either operand is a string. Field and param names
+//! can be paths into nested values, such as `$F(address.city)` or
+//! `$P{customer.tags[0]}`.
+use crate::error::ReportError::{self, *};
+use crate::path;
+use serde_json::Value as JValue;
+use std::collections::HashMap;
+
+/// A parsed expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(JValue),
+    Field(String),
+    Param(String),
+    Variable(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+/// Text with embedded placeholders, e.g. `"Total: ${ $V{sum} / 1000 }k"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextTemplate {
+    pub parts: Vec<TextPart>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextPart {
+    Literal(String),
+    Expr(Expr),
+}
+
+/// A syntax error at a byte offset of the parsed text.
+#[derive(Debug)]
+pub(crate) struct SyntaxError {
+    pub message: String,
+    pub offset: usize,
+}
+
+/// Values visible to an expression while it is evaluated.
+pub(crate) struct Scope<'a> {
+    pub params: &'a HashMap<String, JValue>,
+    pub variables: &'a HashMap<String, JValue>,
+    pub row: Option<&'a JValue>,
+    /// Read a path whose intermediate value is missing as `null` instead of failing.
+    pub blank_missing: bool,
+}
+
+/// Supported functions with their minimum and maximum argument count.
+const FUNCTIONS: &[(&str, usize, usize)] = &[
+    ("upper", 1, 1),
+    ("lower", 1, 1),
+    ("trim", 1, 1),
+    ("len", 1, 1),
+    ("abs", 1, 1),
+    ("round", 1, 2),
+    ("floor", 1, 1),
+    ("ceil", 1, 1),
+    ("min", 1, usize::MAX),
+    ("max", 1, usize::MAX),
+    ("if", 3, 3),
+    ("coalesce", 1, usize::MAX),
+    ("concat", 1, usize::MAX),
+];
+
+impl TextTemplate {
+    pub(crate) fn parse(text: &str) -> Result<TextTemplate, SyntaxError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut parser = Parser { src: text, pos: 0 };
+        while parser.pos < text.len() {
+            let rest = &text[parser.pos..];
+            let expr = if rest.starts_with("${") {
+                parser.pos += 2;
+                let expr = parser.parse_expr()?;
+                parser.expect("}")?;
+                Some(expr)
+            } else if rest.starts_with("$F(") || rest.starts_with("$P{") || rest.starts_with("$V{")
+            {
+                Some(parser.parse_reference()?)
+            } else {
+                None
+            };
+            match expr {
+                Some(expr) => {
+                    if !literal.is_empty() {
+                        parts.push(TextPart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(TextPart::Expr(expr));
+                }
+                None => {
+                    let c = rest.chars().next().unwrap_or_default();
+                    literal.push(c);
+                    parser.pos += c.len_utf8();
+                }
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TextPart::Literal(literal));
+        }
+        Ok(TextTemplate { parts })
+    }
+
+    /// Returns the expression if the text is exactly one placeholder.
+    pub fn as_expr(&self) -> Option<&Expr> {
+        match self.parts.as_slice() {
+            [TextPart::Expr(expr)] => Some(expr),
+            _ => None,
+        }
+    }
+
+    /// Names of all fields referenced by the text.
+    pub fn fields(&self) -> Vec<&str> {
+        self.references(|expr| match expr {
+            Expr::Field(name) => Some(name),
+            _ => None,
+        })
+    }
+
+    /// Names of all params referenced by the text.
+    pub fn params(&self) -> Vec<&str> {
+        self.references(|expr| match expr {
+            Expr::Param(name) => Some(name),
+            _ => None,
+        })
+    }
+
+    /// Names of all variables referenced by the text.
+    pub fn variables(&self) -> Vec<&str> {
+        self.references(|expr| match expr {
+            Expr::Variable(name) => Some(name),
+            _ => None,
+        })
+    }
+
+    fn references<'a>(&'a self, select: impl Fn(&'a Expr) -> Option<&'a String>) -> Vec<&'a str> {
+        let mut names = Vec::new();
+        for part in &self.parts {
+            if let TextPart::Expr(expr) = part {
+                expr.visit(&mut |expr| names.extend(select(expr).map(String::as_str)));
+            }
+        }
+        names
+    }
+
+    pub(crate) fn render(&self, scope: &Scope) -> Result<String, ReportError> {
+        let mut text = String::new();
+        for part in &self.parts {
+            match part {
+                TextPart::Literal(literal) => text.push_str(literal),
+                TextPart::Expr(expr) => text.push_str(&display(&expr.eval(scope)?)),
+            }
+        }
+        Ok(text)
+    }
+}
+
+impl Expr {
+    /// Calls `f` on this expression and all of its subexpressions.
+    fn visit<'a>(&'a self, f: &mut impl FnMut(&'a Expr)) {
+        f(self);
+        match self {
+            Expr::Unary(_, operand) => operand.visit(f),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.visit(f);
+                rhs.visit(f);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.visit(f)),
+            Expr::Literal(_) | Expr::Field(_) | Expr::Param(_) | Expr::Variable(_) => {}
+        }
+    }
+
+    pub(crate) fn eval(&self, scope: &Scope) -> Result<JValue, ReportError> {
+        let value = match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::Field(name) => match scope.row {
+                Some(row) => field_value(row, name, scope.blank_missing)?
+                    .cloned()
+                    .unwrap_or(JValue::Null),
+                None => JValue::Null,
+            },
+            Expr::Param(name) => lookup(name, |key| scope.params.get(key), scope.blank_missing)?
+                .cloned()
+                .unwrap_or(JValue::Null),
+            Expr::Variable(name) => scope.variables.get(name).cloned().unwrap_or(JValue::Null),
+            Expr::Unary(UnaryOp::Neg, operand) => match operand.eval(scope)?.as_f64() {
+                Some(number) => to_number(-number),
+                None => return Err(eval_error("'-' expects a number")),
+            },
+            Expr::Unary(UnaryOp::Not, operand) => JValue::Bool(!is_truthy(&operand.eval(scope)?)),
+            Expr::Binary(BinaryOp::And, lhs, rhs) => {
+                JValue::Bool(is_truthy(&lhs.eval(scope)?) && is_truthy(&rhs.eval(scope)?))
+            }
+            Expr::Binary(BinaryOp::Or, lhs, rhs) => {
+                JValue::Bool(is_truthy(&lhs.eval(scope)?) || is_truthy(&rhs.eval(scope)?))
+            }
+            Expr::Binary(op, lhs, rhs) => binary(*op, lhs.eval(scope)?, rhs.eval(scope)?)?,
+            Expr::Call(name, args) if name == "if" => {
+                if is_truthy(&args[0].eval(scope)?) {
+                    args[1].eval(scope)?
+                } else {
+                    args[2].eval(scope)?
+                }
+            }
+            Expr::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(scope))
+                    .collect::<Result<Vec<_>, _>>()?;
+                call(name, args)?
+            }
+        };
+        Ok(value)
+    }
+}
+
+/// Returns the value of field `name` of `row`, which may be a path such as
+/// `address.city`, or `None` if it is absent.
+pub(crate) fn field_value<'a>(
+    row: &'a JValue,
+    name: &str,
+    blank_missing: bool,
+) -> Result<Option<&'a JValue>, ReportError> {
+    lookup(name, |key| row.get(key), blank_missing)
+}
+
+fn lookup<'a>(
+    name: &str,
+    top: impl Fn(&str) -> Option<&'a JValue>,
+    blank_missing: bool,
+) -> Result<Option<&'a JValue>, ReportError> {
+    match path::resolve(name, top) {
+        Ok(value) => Ok(value),
+        Err(_) if blank_missing => Ok(None),
+        Err(message) => Err(eval_error(message)),
+    }
+}
+
+/// Formats a value for output: strings verbatim, `null` as empty text and
+/// arrays as comma-separated items.
+pub(crate) fn display(value: &JValue) -> String {
+    match value {
+        JValue::Null => String::new(),
+        JValue::String(text) => text.clone(),
+        JValue::Number(number) => number.to_string(),
+        JValue::Array(items) => items.iter().map(display).collect::<Vec<_>>().join(", "),
+        other => other.to_string(),
+    }
+}
+
+/// Converts a computed number to JSON, keeping whole numbers integral.
+pub(crate) fn to_number(number: f64) -> JValue {
+    if number.fract() == 0.0 && number.abs() < i64::MAX as f64 {
+        JValue::from(number as i64)
+    } else {
+        JValue::from(number)
+    }
+}
+
+fn is_truthy(value: &JValue) -> bool {
+    match value {
+        JValue::Null => false,
+        JValue::Bool(value) => *value,
+        JValue::Number(number) => number.as_f64() != Some(0.0),
+        JValue::String(text) => !text.is_empty(),
+        JValue::Array(items) => !items.is_empty(),
+        JValue::Object(_) => true,
+    }
+}
+
+fn eval_error(message: impl Into<String>) -> ReportError {
+    ExpressionEvalError(message.into())
+}
+
+fn binary(op: BinaryOp, lhs: JValue, rhs: JValue) -> Result<JValue, ReportError> {
+    let value = match op {
+        BinaryOp::Add if lhs.is_string() || rhs.is_string() => {
+            JValue::String(display(&lhs) + &display(&rhs))
+        }
+        BinaryOp::Eq => JValue::Bool(equals(&lhs, &rhs)),
+        BinaryOp::Ne => JValue::Bool(!equals(&lhs, &rhs)),
+        BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+            let ordering = match (&lhs, &rhs) {
+                (JValue::String(a), JValue::String(b)) => a.cmp(b),
+                _ => match (lhs.as_f64(), rhs.as_f64()) {
+                    (Some(a), Some(b)) => a.total_cmp(&b),
+                    _ => return Err(eval_error(format!("Cannot compare {} and {}", lhs, rhs))),
+                },
+            };
+            JValue::Bool(match op {
+                BinaryOp::Lt => ordering.is_lt(),
+                BinaryOp::Le => ordering.is_le(),
+                BinaryOp::Gt => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            })
+        }
+        _ => {
+            let (Some(a), Some(b)) = (lhs.as_f64(), rhs.as_f64()) else {
+                return Err(eval_error(format!(
+                    "Arithmetic on non-numbers: {} and {}",
+                    lhs, rhs
+                )));
+            };
+            match op {
+                BinaryOp::Add => to_number(a + b),
+                BinaryOp::Sub => to_number(a - b),
+                BinaryOp::Mul => to_number(a * b),
+                BinaryOp::Div | BinaryOp::Rem if b == 0.0 => {
+                    return Err(eval_error("Division by zero"))
+                }
+                BinaryOp::Div => to_number(a / b),
+                _ => to_number(a % b),
+            }
+        }
+    };
+    Ok(value)
+}
+
+fn equals(lhs: &JValue, rhs: &JValue) -> bool {
+    match (lhs.as_f64(), rhs.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => lhs == rhs,
+    }
+}
+
+fn call(name: &str, args: Vec<JValue>) -> Result<JValue, ReportError> {
+    let number = |index: usize| -> Result<f64, ReportError> {
+        args[index]
+            .as_f64()
+            .ok_or_else(|| eval_error(format!("{}() expects a number, got {}", name, args[index])))
+    };
+    let value = match name {
+        "upper" => JValue::String(display(&args[0]).to_uppercase()),
+        "lower" => JValue::String(display(&args[0]).to_lowercase()),
+        "trim" => JValue::String(display(&args[0]).trim().to_string()),
+        "len" => match &args[0] {
+            JValue::Array(items) => JValue::from(items.len()),
+            other => JValue::from(display(other).chars().count()),
+        },
+        "abs" => to_number(number(0)?.abs()),
+        "round" => {
+            let digits = if args.len() > 1 { number(1)? } else { 0.0 };
+            let factor = 10f64.powi(digits as i32);
+            to_number((number(0)? * factor).round() / factor)
+        }
+        "floor" => to_number(number(0)?.floor()),
+        "ceil" => to_number(number(0)?.ceil()),
+        "min" | "max" => {
+            let mut result: Option<f64> = None;
+            for index in 0..args.len() {
+                let value = number(index)?;
+                result = Some(match result {
+                    Some(current) if name == "min" => current.min(value),
+                    Some(current) => current.max(value),
+                    None => value,
+                });
+            }
+            result.map(to_number).unwrap_or(JValue::Null)
+        }
+        "coalesce" => args
+            .into_iter()
+            .find(|value| !value.is_null())
+            .unwrap_or(JValue::Null),
+        "concat" => JValue::String(args.iter().map(display).collect()),
+        _ => return Err(eval_error(format!("Unknown function '{}'", name))),
+    };
+    Ok(value)
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: impl Into<String>) -> SyntaxError {
+        SyntaxError {
+            message: message.into(),
+            offset: self.pos,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), SyntaxError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(format!("Expected '{}'", token)))
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, SyntaxError> {
+        let mut lhs = self.parse_and()?;
+        while self.eat("||") {
+            let rhs = self.parse_and()?;
+            lhs = Expr::Binary(BinaryOp::Or, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, SyntaxError> {
+        let mut lhs = self.parse_comparison()?;
+        while self.eat("&&") {
+            let rhs = self.parse_comparison()?;
+            lhs = Expr::Binary(BinaryOp::And, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, SyntaxError> {
+        let lhs = self.parse_additive()?;
+        let operators = [
+            ("==", BinaryOp::Eq),
+            ("!=", BinaryOp::Ne),
+            ("<=", BinaryOp::Le),
+            (">=", BinaryOp::Ge),
+            ("<", BinaryOp::Lt),
+            (">", BinaryOp::Gt),
+        ];
+        for (token, op) in operators {
+            if self.eat(token) {
+                let rhs = self.parse_additive()?;
+                return Ok(Expr::Binary(op, Box::new(lhs), Box::new(rhs)));
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, SyntaxError> {
+        let mut lhs = self.parse_multiplicative()?;
+        loop {
+            let op = if self.eat("+") {
+                BinaryOp::Add
+            } else if self.eat("-") {
+                BinaryOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.parse_multiplicative()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr, SyntaxError> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            let op = if self.eat("*") {
+                BinaryOp::Mul
+            } else if self.eat("/") {
+                BinaryOp::Div
+            } else if self.eat("%") {
+                BinaryOp::Rem
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.parse_unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, SyntaxError> {
+        if self.eat("-") {
+            Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.parse_unary()?)))
+        } else if self.eat("!") {
+            Ok(Expr::Unary(UnaryOp::Not, Box::new(self.parse_unary()?)))
+        } else {
+            self.parse_primary()
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, SyntaxError> {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let Some(c) = rest.chars().next() else {
+            return Err(self.error("Unexpected end of expression"));
+        };
+        if c == '(' {
+            self.pos += 1;
+            let expr = self.parse_expr()?;
+            self.expect(")")?;
+            Ok(expr)
+        } else if c == '\'' || c == '"' {
+            self.parse_string(c)
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(rest.len());
+            let number = rest[..len]
+                .parse::<f64>()
+                .map_err(|_| self.error(format!("Invalid number '{}'", &rest[..len])))?;
+            self.pos += len;
+            Ok(Expr::Literal(to_number(number)))
+        } else if c == '$' {
+            self.parse_reference()
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let name = &rest[..len];
+            let start = self.pos;
+            self.pos += len;
+            match name {
+                "true" => Ok(Expr::Literal(JValue::Bool(true))),
+                "false" => Ok(Expr::Literal(JValue::Bool(false))),
+                "null" => Ok(Expr::Literal(JValue::Null)),
+                _ => self.parse_call(name, start),
+            }
+        } else {
+            Err(self.error(format!("Unexpected character '{}'", c)))
+        }
+    }
+
+    fn parse_call(&mut self, name: &str, start: usize) -> Result<Expr, SyntaxError> {
+        let Some(&(_, min, max)) = FUNCTIONS.iter().find(|(function, _, _)| *function == name)
+        else {
+            self.pos = start;
+            return Err(self.error(format!("Unknown function '{}'", name)));
+        };
+        self.expect("(")?;
+        let mut args = Vec::new();
+        if !self.eat(")") {
+            loop {
+                args.push(self.parse_expr()?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        if args.len() < min || args.len() > max {
+            self.pos = start;
+            return Err(self.error(format!(
+                "Wrong number of arguments for '{}': {}",
+                name,
+                args.len()
+            )));
+        }
+        Ok(Expr::Call(name.to_string(), args))
+    }
+
+    fn parse_string(&mut self, quote: char) -> Result<Expr, SyntaxError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut text = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => text.push('\n'),
+                    Some((_, 't')) => text.push('\t'),
+                    Some((_, escaped)) => text.push(escaped),
+                    None => break,
+                },
+                c if c == quote => {
+                    self.pos += index + 1;
+                    return Ok(Expr::Literal(JValue::String(text)));
+                }
+                c => text.push(c),
+            }
+        }
+        self.pos = start;
+        Err(self.error("Unterminated string"))
+    }
+
+    /// Parses `$F(name)`, `$P{name}` or `$V{name}`.
+    fn parse_reference(&mut self) -> Result<Expr, SyntaxError> {
+        let rest = self.rest();
+        let (close, kind): (char, fn(String) -> Expr) = if rest.starts_with("$F(") {
+            (')', Expr::Field)
+        } else if rest.starts_with("$P{") {
+            ('}', Expr::Param)
+        } else if rest.starts_with("$V{") {
+            ('}', Expr::Variable)
+        } else {
+            return Err(self.error("Expected '$F(', '$P{' or '$V{'"));
+        };
+        let Some(len) = rest[3..].find(close) else {
+            return Err(self.error(format!("Expected '{}'", close)));
+        };
+        let name = rest[3..3 + len].trim();
+        if name.is_empty() {
+            return Err(self.error("Empty reference"));
+        }
+        self.pos += 3 + len + 1;
+        Ok(kind(name.to_string()))
+    }
+}
@@ -0,0 +1,126 @@
+use crate::error::ReportError;
+use shiva::core::DocumentType;
+use std::fmt;
+use std::str::FromStr;
+
+/// Format of a generated report. Markdown and XML are left out: shiva fails
+/// to write report documents in them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    Pdf,
+    Html,
+    Text,
+    Json,
+    Csv,
+    Rtf,
+    Docx,
+    Xlsx,
+    Ods,
+}
+
+impl OutputFormat {
+    /// Every format reports can be generated in.
+    pub const ALL: [OutputFormat; 9] = [
+        OutputFormat::Pdf,
+        OutputFormat::Html,
+        OutputFormat::Text,
+        OutputFormat::Json,
+        OutputFormat::Csv,
+        OutputFormat::Rtf,
+        OutputFormat::Docx,
+        OutputFormat::Xlsx,
+        OutputFormat::Ods,
+    ];
+
+    /// Name of the format, as accepted by [`OutputFormat::from_str`].
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Pdf => "pdf",
+            OutputFormat::Html => "html",
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Rtf => "rtf",
+            OutputFormat::Docx => "docx",
+            OutputFormat::Xlsx => "xlsx",
+            OutputFormat::Ods => "ods",
+        }
+    }
+
+    /// MIME type of documents in this format.
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Pdf => "application/pdf",
+            OutputFormat::Html => "text/html",
+            OutputFormat::Text => "text/plain",
+            OutputFormat::Json => "application/json",
+            OutputFormat::Csv => "text/csv",
+            OutputFormat::Rtf => "application/rtf",
+            OutputFormat::Docx => {
+                "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+            }
+            OutputFormat::Xlsx => {
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+            }
+            OutputFormat::Ods => "application/vnd.oasis.opendocument.spreadsheet",
+        }
+    }
+
+    /// File extension of documents in this format, without the dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Text => "txt",
+            format => format.name(),
+        }
+    }
+
+    /// Whether documents in this format are text rather than binary.
+    pub fn is_text(&self) -> bool {
+        matches!(
+            self,
+            OutputFormat::Html
+                | OutputFormat::Text
+                | OutputFormat::Json
+                | OutputFormat::Csv
+                | OutputFormat::Rtf
+        )
+    }
+
+    pub(crate) fn document_type(&self) -> DocumentType {
+        match self {
+            OutputFormat::Pdf => DocumentType::PDF,
+            OutputFormat::Html => DocumentType::HTML,
+            OutputFormat::Text => DocumentType::Text,
+            OutputFormat::Json => DocumentType::Json,
+            OutputFormat::Csv => DocumentType::CSV,
+            OutputFormat::Rtf => DocumentType::RTF,
+            OutputFormat::Docx => DocumentType::DOCX,
+            OutputFormat::Xlsx => DocumentType::XLSX,
+            OutputFormat::Ods => DocumentType::ODS,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = ReportError;
+
+    /// Parses a format name or file extension, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        let name = match name.as_str() {
+            "txt" => "text",
+            "htm" => "html",
+            name => name,
+        };
+        OutputFormat::ALL
+            .into_iter()
+            .find(|format| format.name() == name)
+            .ok_or(ReportError::InvalidDocumentType(s.to_string()))
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
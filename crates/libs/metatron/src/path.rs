@@ -0,0 +1,223 @@
+//! Locations of values in JSON data, written as JSON Pointers (`/result/items`)
+//! or JSONPaths (`$.result.items[*]`).
+
+use serde_json::Value as JValue;
+use std::fmt;
+use std::str::FromStr;
+
+/// Location of values in JSON data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataPath {
+    /// A JSON Pointer (RFC 6901) such as `/result/items`, selecting at most one value.
+    Pointer(String),
+    /// A JSONPath such as `$.result.items` or `$.orders[*].lines`, with the
+    /// path it was parsed from.
+    JsonPath {
+        path: String,
+        segments: Vec<PathSegment>,
+    },
+}
+
+/// A step of a JSONPath.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    /// `.name` or `['name']`: the member of an object.
+    Key(String),
+    /// `[2]`: the element of an array; negative indices count from the end.
+    Index(i64),
+    /// `.*` or `[*]`: every element of an array or member of an object.
+    Wildcard,
+}
+
+impl FromStr for DataPath {
+    type Err = String;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        if path.is_empty() || path.starts_with('/') {
+            return Ok(DataPath::Pointer(path.to_string()));
+        }
+        let Some(rest) = path.strip_prefix('$') else {
+            return Err(format!(
+                "Expected a JSON Pointer starting with '/' or a JSONPath starting with '$': {}",
+                path
+            ));
+        };
+        Ok(DataPath::JsonPath {
+            path: path.to_string(),
+            segments: parse_segments(rest)?,
+        })
+    }
+}
+
+impl fmt::Display for DataPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataPath::Pointer(pointer) => f.write_str(pointer),
+            DataPath::JsonPath { path, .. } => f.write_str(path),
+        }
+    }
+}
+
+impl DataPath {
+    /// Returns the values at this path in `data`, in document order.
+    pub fn select<'a>(&self, data: &'a JValue) -> Vec<&'a JValue> {
+        match self {
+            DataPath::Pointer(pointer) => data.pointer(pointer).into_iter().collect(),
+            DataPath::JsonPath { segments, .. } => select(data, segments),
+        }
+    }
+
+    /// Returns the value at this path in `data` for changing it in place: the
+    /// value a JSON Pointer selects, or the one a JSONPath without wildcards does.
+    pub fn select_mut<'a>(&self, data: &'a mut JValue) -> Option<&'a mut JValue> {
+        let segments = match self {
+            DataPath::Pointer(pointer) => return data.pointer_mut(pointer),
+            DataPath::JsonPath { segments, .. } => segments,
+        };
+        segments
+            .iter()
+            .try_fold(data, |value, segment| match (segment, value) {
+                (PathSegment::Key(key), JValue::Object(object)) => object.get_mut(key),
+                (PathSegment::Index(index), JValue::Array(array)) => {
+                    let position = position(array.len(), *index)?;
+                    array.get_mut(position)
+                }
+                _ => None,
+            })
+    }
+}
+
+/// Returns the values reached from `value` by following `segments`.
+pub(crate) fn select<'a>(value: &'a JValue, segments: &[PathSegment]) -> Vec<&'a JValue> {
+    let mut values = vec![value];
+    for segment in segments {
+        values = values
+            .into_iter()
+            .flat_map(|value| -> Vec<&JValue> {
+                match (segment, value) {
+                    (PathSegment::Key(key), JValue::Object(object)) => {
+                        object.get(key).into_iter().collect()
+                    }
+                    (PathSegment::Index(index), JValue::Array(array)) => {
+                        element(array, *index).into_iter().collect()
+                    }
+                    (PathSegment::Wildcard, JValue::Array(array)) => array.iter().collect(),
+                    (PathSegment::Wildcard, JValue::Object(object)) => object.values().collect(),
+                    _ => Vec::new(),
+                }
+            })
+            .collect();
+    }
+    values
+}
+
+fn element(array: &[JValue], index: i64) -> Option<&JValue> {
+    array.get(position(array.len(), index)?)
+}
+
+/// Position of `index` in an array of `len` elements; negative indices count from the end.
+fn position(len: usize, index: i64) -> Option<usize> {
+    if index < 0 {
+        len.checked_sub(index.unsigned_abs() as usize)
+    } else {
+        Some(index as usize)
+    }
+}
+
+/// Parses the steps of a JSONPath after its `$`: `.name`, `.*`, `['name']`,
+/// `["name"]`, `[2]` and `[*]`.
+pub(crate) fn parse_segments(path: &str) -> Result<Vec<PathSegment>, String> {
+    let mut segments = Vec::new();
+    let mut rest = path;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '.' => {
+                let end = rest[1..].find(['.', '[']).map_or(rest.len(), |end| end + 1);
+                let name = &rest[1..end];
+                segments.push(match name {
+                    "" => return Err(format!("Missing name after '.' in {}", path)),
+                    "*" => PathSegment::Wildcard,
+                    name => PathSegment::Key(name.to_string()),
+                });
+                rest = &rest[end..];
+            }
+            '[' => {
+                let (segment, len) =
+                    parse_bracket(rest).ok_or_else(|| format!("Invalid '{}' in {}", rest, path))?;
+                segments.push(segment);
+                rest = &rest[len..];
+            }
+            _ => return Err(format!("Expected '.' or '[' at '{}' in {}", rest, path)),
+        }
+    }
+    Ok(segments)
+}
+
+/// Parses a `[...]` step at the start of `text`, returning it and its length.
+fn parse_bracket(text: &str) -> Option<(PathSegment, usize)> {
+    let inner = &text[1..];
+    if let Some(quote @ ('\'' | '"')) = inner.chars().next() {
+        let end = inner[1..].find(quote)? + 1;
+        if !inner[end + 1..].starts_with(']') {
+            return None;
+        }
+        let key = inner[1..end].to_string();
+        return Some((PathSegment::Key(key), end + 3));
+    }
+    let end = inner.find(']')?;
+    let segment = match inner[..end].trim() {
+        "*" => PathSegment::Wildcard,
+        index => PathSegment::Index(index.parse().ok()?),
+    };
+    Some((segment, end + 2))
+}
+
+/// Looks up a field path such as `name`, `address.city` or `items[0].sku`,
+/// where `top` finds a top-level field by name. A top-level name containing
+/// `.` or `[` takes precedence over the path it spells.
+///
+/// Returns `None` when the last step of the path is absent, and an error when
+/// an earlier one is absent or `null`.
+pub(crate) fn resolve<'a>(
+    path: &str,
+    top: impl Fn(&str) -> Option<&'a JValue>,
+) -> Result<Option<&'a JValue>, String> {
+    if let Some(value) = top(path) {
+        return Ok(Some(value));
+    }
+    if !path.contains(['.', '[']) {
+        return Ok(None);
+    }
+    let segments = parse_field_path(path)?;
+    let PathSegment::Key(name) = &segments[0] else {
+        return Err(format!("Expected a field name at the start of {}", path));
+    };
+    let mut value = top(name);
+    let mut reached = name.clone();
+    for segment in &segments[1..] {
+        let Some(parent) = value.filter(|value| !value.is_null()) else {
+            return Err(format!("Missing '{}' in '{}'", reached, path));
+        };
+        value = match segment {
+            PathSegment::Key(key) => {
+                reached = format!("{}.{}", reached, key);
+                parent.get(key)
+            }
+            PathSegment::Index(index) => {
+                reached = format!("{}[{}]", reached, index);
+                parent.as_array().and_then(|array| element(array, *index))
+            }
+            PathSegment::Wildcard => unreachable!("rejected by parse_field_path"),
+        };
+    }
+    Ok(value)
+}
+
+/// Parses a field path such as `address.city` or `items[0].sku`.
+pub(crate) fn parse_field_path(path: &str) -> Result<Vec<PathSegment>, String> {
+    let segments = parse_segments(&format!(".{}", path))?;
+    if segments.contains(&PathSegment::Wildcard) {
+        return Err(format!("Wildcards are not allowed in {}", path));
+    }
+    Ok(segments)
+}
@@ -0,0 +1,629 @@
+use crate::data::{parse_params, CsvOptions, CsvSource, Data, DataSource};
+use crate::error::ReportError::{self, *};
+use crate::expr::{display, field_value, to_number, Expr, Scope, TextTemplate};
+use crate::format::OutputFormat;
+use crate::resource::{ImagesOrFileSystem, ResourceResolver};
+use crate::template::{BandElement, Calculation, CompiledTemplate, RowValue, Variable};
+use bytes::Bytes;
+use serde_json::Value as JValue;
+use shiva::core::Element::{Header, Paragraph, Table, Text};
+use shiva::core::{
+    Document, Element, ImageData, ImageDimension, ImageType, TableCell, TableHeader, TableRow,
+};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::debug;
+
+impl CompiledTemplate {
+    /// Renders with images taken from `images` or, failing that, read from
+    /// disk. Use [`CompiledTemplate::render_with_resolver`] for untrusted templates.
+    pub fn render(
+        &self,
+        data: &str,
+        images: &HashMap<String, Bytes>,
+        format: OutputFormat,
+    ) -> Result<Bytes, ReportError> {
+        self.render_with_resolver(data, &ImagesOrFileSystem { images }, format)
+    }
+
+    pub fn render_with_resolver(
+        &self,
+        data: &str,
+        resolver: &dyn ResourceResolver,
+        format: OutputFormat,
+    ) -> Result<Bytes, ReportError> {
+        let data = Data::from_json(data, &self.data)?;
+        self.generate(
+            &mut data.rows.into_iter().map(Ok),
+            data.params,
+            &data.datasets,
+            resolver,
+            format,
+        )
+    }
+
+    /// Renders rows read from `csv` with the params of `params`, a JSON object.
+    pub fn render_csv_with_resolver(
+        &self,
+        csv: &[u8],
+        params: Option<&str>,
+        options: &CsvOptions,
+        resolver: &dyn ResourceResolver,
+        format: OutputFormat,
+    ) -> Result<Bytes, ReportError> {
+        let mut rows = CsvSource::new(csv, options)?;
+        self.render_source_with_resolver(&mut rows, params, resolver, format)
+    }
+
+    /// Renders the rows of `rows` as they are read, with the params of
+    /// `params`, a JSON object. Only sorted groups and rows referring to
+    /// variables need every row in memory at once.
+    pub fn render_source(
+        &self,
+        rows: &mut dyn DataSource,
+        params: Option<&str>,
+        images: &HashMap<String, Bytes>,
+        format: OutputFormat,
+    ) -> Result<Bytes, ReportError> {
+        self.render_source_with_resolver(rows, params, &ImagesOrFileSystem { images }, format)
+    }
+
+    pub fn render_source_with_resolver(
+        &self,
+        rows: &mut dyn DataSource,
+        params: Option<&str>,
+        resolver: &dyn ResourceResolver,
+        format: OutputFormat,
+    ) -> Result<Bytes, ReportError> {
+        let params = parse_params(params)?;
+        self.generate(rows, params, &HashMap::new(), resolver, format)
+    }
+
+    fn generate(
+        &self,
+        rows: &mut dyn DataSource,
+        params: HashMap<String, JValue>,
+        datasets: &HashMap<String, Vec<JValue>>,
+        resolver: &dyn ResourceResolver,
+        format: OutputFormat,
+    ) -> Result<Bytes, ReportError> {
+        let document = self.document(rows, params, datasets, resolver)?;
+
+        let result = document.generate(format.document_type());
+
+        match result {
+            Ok(bytes) => Ok(bytes),
+            Err(e) => Err(ReportError::Common(e.to_string())),
+        }
+    }
+
+    pub fn to_document(
+        &self,
+        data: &str,
+        images: &HashMap<String, Bytes>,
+    ) -> Result<Document, ReportError> {
+        self.to_document_with_resolver(data, &ImagesOrFileSystem { images })
+    }
+
+    pub fn to_document_with_resolver(
+        &self,
+        data: &str,
+        resolver: &dyn ResourceResolver,
+    ) -> Result<Document, ReportError> {
+        let data = Data::from_json(data, &self.data)?;
+        self.document(
+            &mut data.rows.into_iter().map(Ok),
+            data.params,
+            &data.datasets,
+            resolver,
+        )
+    }
+
+    fn document(
+        &self,
+        rows: &mut dyn DataSource,
+        params: HashMap<String, JValue>,
+        datasets: &HashMap<String, Vec<JValue>>,
+        resolver: &dyn ResourceResolver,
+    ) -> Result<Document, ReportError> {
+        match &self.datasource {
+            Some(datasource) => datasource.query(params, resolver, |rows, params| {
+                self.document_of_rows(rows, params, datasets, resolver)
+            }),
+            None => self.document_of_rows(rows, params, datasets, resolver),
+        }
+    }
+
+    fn document_of_rows(
+        &self,
+        rows: &mut dyn DataSource,
+        params: HashMap<String, JValue>,
+        datasets: &HashMap<String, Vec<JValue>>,
+        resolver: &dyn ResourceResolver,
+    ) -> Result<Document, ReportError> {
+        let mut bindings = Bindings {
+            params,
+            variables: self.dataset_variables(datasets)?,
+            blank_missing: self.blank_missing,
+        };
+        let mut table = TableRows::new(self);
+
+        // Rows are rendered as they are read, unless they have to be sorted
+        // or refer to variables, which are only known once every row is read.
+        let needs_all_rows = self.groups.iter().any(|group| group.sort)
+            || self
+                .row
+                .iter()
+                .any(|row_value| !row_value.value.variables().is_empty());
+        if needs_all_rows {
+            let mut data_rows = rows
+                .enumerate()
+                .map(|(index, row)| row.map(|row| (index, row)))
+                .collect::<Result<Vec<_>, _>>()?;
+            data_rows.sort_by(|(_, a), (_, b)| {
+                self.groups
+                    .iter()
+                    .filter(|group| group.sort)
+                    .map(|group| compare_values(key(a, &group.field), key(b, &group.field)))
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or(Ordering::Equal)
+            });
+            let mut totals = self.accumulators();
+            for (_, data_row) in &data_rows {
+                self.accumulate(&mut totals, data_row);
+            }
+            bindings.variables.extend(self.values(&totals));
+            for (index, data_row) in data_rows {
+                table.push(index, data_row, &bindings)?;
+            }
+        } else {
+            for (index, data_row) in rows.enumerate() {
+                table.push(index, data_row?, &bindings)?;
+            }
+        }
+        table.close_groups(0, &bindings)?;
+        bindings.variables.extend(self.values(&table.totals));
+
+        let mut elements = render_band(&self.title, &bindings, resolver)?;
+
+        if self.has_table() {
+            elements.push(self.render_table(table.rows, &bindings)?);
+        }
+
+        let page_header = render_band(&self.page_header, &bindings, resolver)?;
+        let page_footer = render_band(&self.page_footer, &bindings, resolver)?;
+        debug!("{:?}", page_footer);
+
+        elements.extend(render_band(&self.summary, &bindings, resolver)?);
+
+        let mut document = Document::new(elements);
+        document.page_header = page_header;
+        document.page_footer = page_footer;
+        Ok(document)
+    }
+}
+
+impl CompiledTemplate {
+    /// Whether the template declares a table: a column header, row or column footer.
+    fn has_table(&self) -> bool {
+        !(self.column_header.is_empty() && self.row.is_empty() && self.column_footer.is_empty())
+    }
+
+    fn render_table(
+        &self,
+        mut rows: Vec<TableRow>,
+        bindings: &Bindings,
+    ) -> Result<Element, ReportError> {
+        let headers = self
+            .column_header
+            .iter()
+            .map(|column| TableHeader {
+                element: Text {
+                    text: column.name.clone(),
+                    size: 8,
+                },
+                width: column.width,
+            })
+            .collect();
+
+        if let Some(footer_row) = render_values(&self.column_footer, bindings, None)? {
+            rows.push(footer_row);
+        }
+
+        Ok(Table { headers, rows })
+    }
+
+    fn accumulators(&self) -> Vec<Accumulator> {
+        vec![Accumulator::default(); self.variables.len()]
+    }
+
+    /// Adds `data_row` to the variables over the rows of the table.
+    fn accumulate(&self, accumulators: &mut [Accumulator], data_row: &JValue) {
+        for (accumulator, variable) in accumulators.iter_mut().zip(&self.variables) {
+            if variable.dataset.is_none() {
+                accumulator.add(variable, data_row);
+            }
+        }
+    }
+
+    /// Computes the variables over datasets, which do not change with the rows of the table.
+    fn dataset_variables(
+        &self,
+        datasets: &HashMap<String, Vec<JValue>>,
+    ) -> Result<HashMap<String, JValue>, ReportError> {
+        let mut values = HashMap::new();
+        for variable in &self.variables {
+            let Some(name) = &variable.dataset else {
+                continue;
+            };
+            let rows = datasets.get(name).ok_or_else(|| {
+                InvalidData(format!(
+                    "Dataset '{}' of variable '{}' is only available in JSON data",
+                    name, variable.name
+                ))
+            })?;
+            let mut accumulator = Accumulator::default();
+            for row in rows {
+                accumulator.add(variable, row);
+            }
+            values.insert(variable.name.clone(), accumulator.value(variable.calc));
+        }
+        Ok(values)
+    }
+
+    /// Values of the variables over the rows of the table, from their `accumulators`.
+    fn values(&self, accumulators: &[Accumulator]) -> HashMap<String, JValue> {
+        self.variables
+            .iter()
+            .zip(accumulators)
+            .filter(|(variable, _)| variable.dataset.is_none())
+            .map(|(variable, accumulator)| {
+                (variable.name.clone(), accumulator.value(variable.calc))
+            })
+            .collect()
+    }
+
+    /// Fails if `data_row` lacks a field used by `row` or `group`.
+    fn check_fields(&self, index: usize, data_row: &JValue) -> Result<(), ReportError> {
+        let groups = self.groups.iter().map(|group| group.field.as_str());
+        let mut fields = groups.chain(
+            self.row
+                .iter()
+                .flat_map(|row_value| row_value.value.fields()),
+        );
+        match fields.find(|field| !matches!(field_value(data_row, field, false), Ok(Some(_)))) {
+            Some(field) => Err(MissingField {
+                row: index,
+                field: field.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn render_row(&self, data_row: &JValue, bindings: &Bindings) -> Result<TableRow, ReportError> {
+        let scope = bindings.scope(Some(data_row));
+        let mut cells = Vec::new();
+        for row_value in &self.row {
+            let mut text = render_row_value(row_value, &scope, data_row)?;
+            if text.is_empty() {
+                text = " ".to_string();
+            }
+            cells.push(TableCell {
+                element: Text { text, size: 8 }, // Default font size for cells
+            });
+        }
+        Ok(TableRow { cells })
+    }
+}
+
+/// Table rows rendered from data rows as they arrive, in a single pass.
+/// Each run of equal keys of a group is framed by the group header and
+/// footer, which are rendered when the run ends, as they may refer to the
+/// variables of the group.
+struct TableRows<'t> {
+    template: &'t CompiledTemplate,
+    /// Variables over all rows.
+    totals: Vec<Accumulator>,
+    /// Groups of the current row, outermost first.
+    open: Vec<OpenGroup>,
+    rows: Vec<TableRow>,
+}
+
+/// A run of rows of a group level, kept until its key changes.
+struct OpenGroup {
+    key: JValue,
+    /// The first row of the run, in which the header and footer are rendered,
+    /// and its index in the data.
+    first_row: JValue,
+    index: usize,
+    accumulators: Vec<Accumulator>,
+    rows: Vec<TableRow>,
+}
+
+impl<'t> TableRows<'t> {
+    fn new(template: &'t CompiledTemplate) -> Self {
+        TableRows {
+            template,
+            totals: template.accumulators(),
+            open: Vec::new(),
+            rows: Vec::new(),
+        }
+    }
+
+    /// Renders `data_row`, the row at `index` in the data.
+    fn push(
+        &mut self,
+        index: usize,
+        data_row: JValue,
+        bindings: &Bindings,
+    ) -> Result<(), ReportError> {
+        let template = self.template;
+        if template.strict {
+            template.check_fields(index, &data_row)?;
+        }
+        let changed = self
+            .open
+            .iter()
+            .zip(&template.groups)
+            .position(|(open, group)| open.key != *key(&data_row, &group.field))
+            .unwrap_or(self.open.len());
+        self.close_groups(changed, bindings)?;
+        for group in &template.groups[self.open.len()..] {
+            self.open.push(OpenGroup {
+                key: key(&data_row, &group.field).clone(),
+                first_row: data_row.clone(),
+                index,
+                accumulators: template.accumulators(),
+                rows: Vec::new(),
+            });
+        }
+
+        let row = template
+            .render_row(&data_row, bindings)
+            .map_err(|error| row_error(index, error))?;
+        template.accumulate(&mut self.totals, &data_row);
+        for open in &mut self.open {
+            template.accumulate(&mut open.accumulators, &data_row);
+        }
+        match self.open.last_mut() {
+            Some(open) => open.rows.push(row),
+            None => self.rows.push(row),
+        }
+        Ok(())
+    }
+
+    /// Ends the runs of the group levels from `level` inward.
+    fn close_groups(&mut self, level: usize, bindings: &Bindings) -> Result<(), ReportError> {
+        while self.open.len() > level {
+            let open = self.open.pop().expect("a group is open");
+            let group = &self.template.groups[self.open.len()];
+            let group_bindings = bindings.with_variables(self.template.values(&open.accumulators));
+            let header = render_values(&group.header, &group_bindings, Some(&open.first_row))
+                .map_err(|error| row_error(open.index, error))?;
+            let footer = render_values(&group.footer, &group_bindings, Some(&open.first_row))
+                .map_err(|error| row_error(open.index, error))?;
+            let rows = match self.open.last_mut() {
+                Some(parent) => &mut parent.rows,
+                None => &mut self.rows,
+            };
+            rows.extend(header);
+            rows.extend(open.rows);
+            rows.extend(footer);
+        }
+        Ok(())
+    }
+}
+
+fn render_row_value(
+    row_value: &RowValue,
+    scope: &Scope,
+    data_row: &JValue,
+) -> Result<String, ReportError> {
+    let Some(expr) = row_value.value.as_expr() else {
+        return row_value.value.render(scope);
+    };
+    if let Expr::Field(name) = expr {
+        if field_value(data_row, name, scope.blank_missing)?.is_none() {
+            let text = row_value
+                .default_text
+                .as_ref()
+                .or(row_value.null_text.as_ref());
+            return Ok(text.cloned().unwrap_or_default());
+        }
+    }
+    let text = match expr.eval(scope)? {
+        JValue::Null => row_value.null_text.clone().unwrap_or_default(),
+        JValue::Bool(true) => row_value.true_text.clone().unwrap_or("true".to_string()),
+        JValue::Bool(false) => row_value.false_text.clone().unwrap_or("false".to_string()),
+        value => display(&value),
+    };
+    Ok(text)
+}
+
+/// Returns field `name` of `data_row` as a group key or a value to aggregate:
+/// `null` if it is absent, even when its path is broken off early.
+fn key<'a>(data_row: &'a JValue, name: &str) -> &'a JValue {
+    match field_value(data_row, name, true) {
+        Ok(Some(value)) => value,
+        _ => &JValue::Null,
+    }
+}
+
+/// Attributes an error raised while rendering data row `row` to that row.
+fn row_error(row: usize, error: ReportError) -> ReportError {
+    DataRow {
+        row,
+        source: Box::new(error),
+    }
+}
+
+/// Guesses the image type from the extension of `src`, defaulting to PNG.
+fn image_type(src: &str) -> ImageType {
+    let extension = Path::new(src)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    match extension.as_str() {
+        "jpg" => ImageType::Jpeg,
+        extension => ImageType::from_str(extension).unwrap_or_default(),
+    }
+}
+
+/// Renders a row of footer-like values, or `None` if there are no values.
+fn render_values(
+    values: &[TextTemplate],
+    bindings: &Bindings,
+    data_row: Option<&JValue>,
+) -> Result<Option<TableRow>, ReportError> {
+    if values.is_empty() {
+        return Ok(None);
+    }
+    let mut cells = Vec::new();
+    for value in values {
+        let mut resolved_text = value.render(&bindings.scope(data_row))?;
+        if resolved_text.is_empty() {
+            resolved_text = " ".to_string();
+        }
+        cells.push(TableCell {
+            element: Text {
+                text: resolved_text,
+                size: 8,
+            },
+        });
+    }
+    Ok(Some(TableRow { cells }))
+}
+
+fn render_band(
+    band: &[BandElement],
+    bindings: &Bindings,
+    resolver: &dyn ResourceResolver,
+) -> Result<Vec<Element>, ReportError> {
+    let mut elements = Vec::new();
+    for element in band {
+        elements.push(render_element(element, bindings, resolver)?);
+    }
+    Ok(elements)
+}
+
+fn render_element(
+    element: &BandElement,
+    bindings: &Bindings,
+    resolver: &dyn ResourceResolver,
+) -> Result<Element, ReportError> {
+    let element = match element {
+        BandElement::Header { level, text } => Header {
+            level: *level,
+            text: text.render(&bindings.scope(None))?,
+        },
+        BandElement::Text { text, size } => Text {
+            text: text.render(&bindings.scope(None))?,
+            size: *size,
+        },
+        BandElement::Paragraph { elements } => Paragraph {
+            elements: render_band(elements, bindings, resolver)?,
+        },
+        BandElement::Image {
+            src,
+            width,
+            height,
+            align,
+            alt,
+            title,
+        } => {
+            let bytes = resolver
+                .resolve(src)?
+                .ok_or(ImageNotFound(src.to_string()))?;
+            Element::Image(ImageData::new(
+                bytes,
+                title.clone().unwrap_or_default(),
+                alt.clone().unwrap_or_default(),
+                image_type(src).to_string(),
+                align.clone().unwrap_or_default(),
+                ImageDimension {
+                    width: width.clone(),
+                    height: height.clone(),
+                },
+            ))
+        }
+    };
+    Ok(element)
+}
+
+/// Values available for substitution in template text.
+#[derive(Clone)]
+struct Bindings {
+    params: HashMap<String, JValue>,
+    variables: HashMap<String, JValue>,
+    blank_missing: bool,
+}
+
+impl Bindings {
+    fn scope<'a>(&'a self, row: Option<&'a JValue>) -> Scope<'a> {
+        Scope {
+            params: &self.params,
+            variables: &self.variables,
+            row,
+            blank_missing: self.blank_missing,
+        }
+    }
+
+    /// Returns a copy of these bindings with `variables` taking precedence.
+    fn with_variables(&self, variables: HashMap<String, JValue>) -> Bindings {
+        let mut bindings = self.clone();
+        bindings.variables.extend(variables);
+        bindings
+    }
+}
+
+/// Orders JSON values for group sorting: numbers numerically, everything else as text.
+fn compare_values(a: &JValue, b: &JValue) -> Ordering {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        _ => display(a).cmp(&display(b)),
+    }
+}
+
+/// Running state of a variable, fed one row at a time. Non-numeric values
+/// are skipped, except by `count`, which counts every non-null value.
+#[derive(Debug, Clone, Default)]
+struct Accumulator {
+    count: usize,
+    numbers: usize,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Accumulator {
+    fn add(&mut self, variable: &Variable, data_row: &JValue) {
+        let value = match &variable.field {
+            Some(field) => key(data_row, field),
+            None => data_row,
+        };
+        if !value.is_null() {
+            self.count += 1;
+        }
+        if let Some(number) = value.as_f64() {
+            self.numbers += 1;
+            self.sum += number;
+            self.min = Some(self.min.map_or(number, |min| min.min(number)));
+            self.max = Some(self.max.map_or(number, |max| max.max(number)));
+        }
+    }
+
+    fn value(&self, calc: Calculation) -> JValue {
+        let result = match calc {
+            Calculation::Count => return JValue::from(self.count),
+            Calculation::Sum => Some(self.sum),
+            Calculation::Avg if self.numbers == 0 => None,
+            Calculation::Avg => Some(self.sum / self.numbers as f64),
+            Calculation::Min => self.min,
+            Calculation::Max => self.max,
+        };
+        result.map(to_number).unwrap_or(JValue::Null)
+    }
+}
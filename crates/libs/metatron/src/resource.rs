@@ -0,0 +1,94 @@
+use crate::error::ReportError::{self, *};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+/// Source of external resources referenced by a template, such as `image src`.
+pub trait ResourceResolver: Send + Sync {
+    /// Returns the resource named `name`, `None` if it does not exist, or an
+    /// error if access to it is not allowed.
+    fn resolve(&self, name: &str) -> Result<Option<Bytes>, ReportError>;
+}
+
+/// Resolves resources from an in-memory map.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryResolver {
+    resources: HashMap<String, Bytes>,
+}
+
+impl MemoryResolver {
+    pub fn new(resources: HashMap<String, Bytes>) -> Self {
+        Self { resources }
+    }
+}
+
+impl ResourceResolver for MemoryResolver {
+    fn resolve(&self, name: &str) -> Result<Option<Bytes>, ReportError> {
+        Ok(self.resources.get(name).cloned())
+    }
+}
+
+/// Resolves resources as relative paths inside a root directory. Absolute
+/// paths, `..` components and symlinks leading outside the root are denied.
+#[derive(Debug, Clone)]
+pub struct DirectoryResolver {
+    root: PathBuf,
+}
+
+impl DirectoryResolver {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl ResourceResolver for DirectoryResolver {
+    fn resolve(&self, name: &str) -> Result<Option<Bytes>, ReportError> {
+        if name.is_empty() {
+            return Ok(None);
+        }
+        let path = Path::new(name);
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(ResourceAccessDenied(name.to_string()));
+        }
+        let root = self.root.canonicalize()?;
+        let Ok(path) = root.join(path).canonicalize() else {
+            return Ok(None);
+        };
+        if !path.starts_with(&root) {
+            return Err(ResourceAccessDenied(name.to_string()));
+        }
+        // Directories, such as `.`, are not resources.
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(Bytes::from(std::fs::read(path)?)))
+    }
+}
+
+/// Denies access to every resource.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DenyAllResolver;
+
+impl ResourceResolver for DenyAllResolver {
+    fn resolve(&self, name: &str) -> Result<Option<Bytes>, ReportError> {
+        Err(ResourceAccessDenied(name.to_string()))
+    }
+}
+
+/// Resolver behind the `images` map APIs: the map first, then any path on
+/// disk. Only suitable for trusted templates.
+pub(crate) struct ImagesOrFileSystem<'a> {
+    pub images: &'a HashMap<String, Bytes>,
+}
+
+impl ResourceResolver for ImagesOrFileSystem<'_> {
+    fn resolve(&self, name: &str) -> Result<Option<Bytes>, ReportError> {
+        if let Some(bytes) = self.images.get(name) {
+            return Ok(Some(bytes.clone()));
+        }
+        Ok(std::fs::read(name).ok().map(Bytes::from))
+    }
+}
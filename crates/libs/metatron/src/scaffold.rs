@@ -0,0 +1,231 @@
+//! Generates a starting template from sample data.
+
+use crate::error::ReportError;
+use serde_json::Value as JValue;
+use std::fmt::Write;
+
+/// Type of the values of a column, widened as rows disagree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Integer,
+    Float,
+    Bool,
+    Text,
+    /// Objects and arrays, which cannot be shown in a cell.
+    Nested,
+}
+
+impl Kind {
+    fn of(value: &JValue) -> Option<Kind> {
+        match value {
+            JValue::Null => None,
+            JValue::Bool(_) => Some(Kind::Bool),
+            JValue::Number(n) if n.is_f64() => Some(Kind::Float),
+            JValue::Number(_) => Some(Kind::Integer),
+            JValue::String(_) => Some(Kind::Text),
+            JValue::Array(_) | JValue::Object(_) => Some(Kind::Nested),
+        }
+    }
+
+    fn merge(self, other: Kind) -> Kind {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Kind::Integer, Kind::Float) | (Kind::Float, Kind::Integer) => Kind::Float,
+            (Kind::Nested, _) | (_, Kind::Nested) => Kind::Nested,
+            _ => Kind::Text,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, Kind::Integer | Kind::Float)
+    }
+}
+
+struct ColumnInfo {
+    field: String,
+    kind: Option<Kind>,
+    /// Longest value, in characters.
+    len: usize,
+}
+
+/// Column widths are the length of the longest value or name, within these bounds.
+const MIN_WIDTH: usize = 8;
+const MAX_WIDTH: usize = 40;
+
+pub(crate) fn scaffold(data: &str) -> Result<String, ReportError> {
+    let data: JValue = serde_json::from_str(data)?;
+    let Some(data) = data.as_object() else {
+        return Err(ReportError::InvalidData(
+            "Data must be a JSON object with 'rows' and 'params'".to_string(),
+        ));
+    };
+    let rows = match data.get("rows") {
+        Some(JValue::Array(rows)) => rows.as_slice(),
+        Some(_) => {
+            return Err(ReportError::InvalidData(
+                "'rows' must be an array".to_string(),
+            ))
+        }
+        None => &[],
+    };
+
+    let mut columns: Vec<ColumnInfo> = Vec::new();
+    for row in rows.iter().filter_map(JValue::as_object) {
+        for (field, value) in row {
+            let index = match columns.iter().position(|column| &column.field == field) {
+                Some(index) => index,
+                None => {
+                    columns.push(ColumnInfo {
+                        field: field.clone(),
+                        kind: None,
+                        len: 0,
+                    });
+                    columns.len() - 1
+                }
+            };
+            let column = &mut columns[index];
+            if let Some(kind) = Kind::of(value) {
+                column.kind = Some(column.kind.map_or(kind, |known| known.merge(kind)));
+            }
+            let len = match value {
+                JValue::String(s) => s.chars().count(),
+                JValue::Null => 0,
+                value => value.to_string().chars().count(),
+            };
+            column.len = column.len.max(len);
+        }
+    }
+    // Names that would end a `$F(...)` or `$V{...}` reference early cannot be referred to.
+    let (columns, skipped): (Vec<_>, Vec<_>) = columns.into_iter().partition(|column| {
+        column.kind != Some(Kind::Nested)
+            && !column.field.is_empty()
+            && !column.field.contains([')', '}'])
+            && column.field.trim() == column.field
+    });
+    let params: Vec<&String> = match data.get("params") {
+        Some(JValue::Object(params)) => params
+            .iter()
+            .filter(|(key, value)| {
+                !value.is_object()
+                    && !value.is_array()
+                    && !key.is_empty()
+                    && !key.contains('}')
+                    && key.trim() == *key
+            })
+            .map(|(key, _)| key)
+            .collect(),
+        _ => Vec::new(),
+    };
+    let numeric: Vec<&ColumnInfo> = columns
+        .iter()
+        .filter(|column| column.kind.is_some_and(Kind::is_numeric))
+        .collect();
+
+    let mut out = String::from("template {\n");
+    for column in &numeric {
+        writeln!(
+            out,
+            "    variable name={} calc=\"avg\" field={}",
+            quote(&format!("average_{}", column.field)),
+            quote(&column.field)
+        )
+        .unwrap();
+    }
+    if !columns.is_empty() {
+        // Without a field, `count` counts rows rather than non-null values.
+        out.push_str("    variable name=\"row_count\" calc=\"count\"\n");
+    }
+    out.push_str("    title {\n        header level=1 \"Report\"\n    }\n");
+    if !columns.is_empty() {
+        out.push_str("    column_header {\n");
+        for column in &columns {
+            let title = title_case(&column.field);
+            let width = column
+                .len
+                .max(title.chars().count())
+                .clamp(MIN_WIDTH, MAX_WIDTH);
+            writeln!(out, "        column name={} width={}", quote(&title), width).unwrap();
+        }
+        out.push_str("    }\n    row {\n");
+        for column in &columns {
+            writeln!(
+                out,
+                "        value {}",
+                quote(&format!("$F({})", column.field))
+            )
+            .unwrap();
+        }
+        out.push_str("    }\n");
+    }
+    if !numeric.is_empty() {
+        out.push_str("    column_footer {\n");
+        let mut labelled = false;
+        for column in &columns {
+            let value = if column.kind.is_some_and(Kind::is_numeric) {
+                format!("$V{{average_{}}}", column.field)
+            } else if !labelled {
+                labelled = true;
+                "Average:".to_string()
+            } else {
+                String::new()
+            };
+            writeln!(out, "        value {}", quote(&value)).unwrap();
+        }
+        out.push_str("    }\n");
+    }
+    out.push_str("    page_footer {\n        text size=7 \"Generated with Metatron\"\n    }\n");
+    if !columns.is_empty() || !params.is_empty() {
+        out.push_str("    summary {\n        paragraph {\n");
+        if !columns.is_empty() {
+            out.push_str("            text size=10 \"Rows: $V{row_count}\"\n");
+        }
+        for key in params {
+            let text = format!("{}: $P{{{}}}", title_case(key), key);
+            writeln!(out, "            text size=10 {}", quote(&text)).unwrap();
+        }
+        out.push_str("        }\n    }\n");
+    }
+    for column in skipped {
+        writeln!(
+            out,
+            "    // Field {} is not shown: it is nested or its name cannot be referred to",
+            quote(&column.field)
+        )
+        .unwrap();
+    }
+    out.push_str("}\n");
+    Ok(out)
+}
+
+/// `company_name` becomes `Company Name`.
+fn title_case(key: &str) -> String {
+    key.split(['_', '-', ' '])
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// KDL string literal.
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => write!(quoted, "\\u{{{:x}}}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,128 @@
+//! Rows of a `datasource type="sqlite"`, queried from a database provided by
+//! the resource resolver.
+
+use crate::data::DataSource;
+use crate::error::ReportError::{self, *};
+use crate::resource::ResourceResolver;
+use crate::template::SqliteSource;
+use rusqlite::limits::Limit;
+use rusqlite::types::{Value as SqlValue, ValueRef};
+use rusqlite::{Connection, Rows, MAIN_DB};
+use serde_json::{Map, Number, Value as JValue};
+use std::collections::HashMap;
+
+impl SqliteSource {
+    /// Runs the query against a read-only copy of the database, binding its
+    /// parameters to `params`, and hands its rows to `read` along with
+    /// `params`. Each result row becomes an object keyed by column name, read
+    /// from the database only as `read` consumes it. The database file itself
+    /// is loaded into memory as a whole, since it comes from the resolver.
+    pub(crate) fn query<T>(
+        &self,
+        params: HashMap<String, JValue>,
+        resolver: &dyn ResourceResolver,
+        read: impl FnOnce(&mut dyn DataSource, HashMap<String, JValue>) -> Result<T, ReportError>,
+    ) -> Result<T, ReportError> {
+        let database = resolver
+            .resolve(&self.path)?
+            .ok_or_else(|| DataSourceError(format!("Database not found: {}", self.path)))?;
+        let mut connection = Connection::open_in_memory().map_err(sql_error)?;
+        // Queries must not reach other files through `ATTACH`.
+        connection
+            .set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0)
+            .map_err(sql_error)?;
+        connection
+            .deserialize_read_exact(MAIN_DB, &database[..], database.len(), true)
+            .map_err(sql_error)?;
+        drop(database);
+
+        let mut statement = connection.prepare(&self.query).map_err(sql_error)?;
+        if !statement.readonly() {
+            return Err(DataSourceError(
+                "The query must not modify the database".to_string(),
+            ));
+        }
+        for index in 1..=statement.parameter_count() {
+            let name = statement.parameter_name(index).ok_or_else(|| {
+                DataSourceError(format!(
+                    "Parameter {} of the query has no name; use :name",
+                    index
+                ))
+            })?;
+            // The name includes its `:`, `@` or `$` prefix.
+            let key = &name[1..];
+            let value = params
+                .get(key)
+                .ok_or_else(|| DataSourceError(format!("Missing param '{}' for the query", key)))?;
+            statement
+                .raw_bind_parameter(index, to_sql(value))
+                .map_err(sql_error)?;
+        }
+
+        let columns: Vec<String> = statement
+            .column_names()
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let mut rows = QueryRows {
+            rows: statement.raw_query(),
+            columns,
+        };
+        read(&mut rows, params)
+    }
+}
+
+/// Result rows of a query, converted as they are stepped through.
+struct QueryRows<'stmt> {
+    rows: Rows<'stmt>,
+    columns: Vec<String>,
+}
+
+impl Iterator for QueryRows<'_> {
+    type Item = Result<JValue, ReportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = match self.rows.next() {
+            Ok(row) => row?,
+            Err(e) => return Some(Err(sql_error(e))),
+        };
+        let mut object = Map::new();
+        for (index, column) in self.columns.iter().enumerate() {
+            match row.get_ref(index) {
+                Ok(value) => object.insert(column.clone(), to_json(value)),
+                Err(e) => return Some(Err(sql_error(e))),
+            };
+        }
+        Some(Ok(JValue::Object(object)))
+    }
+}
+
+fn sql_error(error: rusqlite::Error) -> ReportError {
+    DataSourceError(error.to_string())
+}
+
+/// Booleans bind as 0 and 1, arrays and objects as JSON text.
+fn to_sql(value: &JValue) -> SqlValue {
+    match value {
+        JValue::Null => SqlValue::Null,
+        JValue::Bool(b) => SqlValue::Integer(*b as i64),
+        JValue::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        JValue::String(s) => SqlValue::Text(s.clone()),
+        value => SqlValue::Text(value.to_string()),
+    }
+}
+
+/// Blobs are read as UTF-8 text, with invalid sequences replaced.
+fn to_json(value: ValueRef) -> JValue {
+    match value {
+        ValueRef::Null => JValue::Null,
+        ValueRef::Integer(i) => JValue::Number(i.into()),
+        ValueRef::Real(f) => Number::from_f64(f).map_or(JValue::Null, JValue::Number),
+        ValueRef::Text(bytes) | ValueRef::Blob(bytes) => {
+            JValue::String(String::from_utf8_lossy(bytes).into_owned())
+        }
+    }
+}
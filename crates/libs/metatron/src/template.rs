@@ -0,0 +1,672 @@
+use crate::error::ReportError::{self, *};
+use crate::error::SourceLocation;
+use crate::expr::TextTemplate;
+use crate::path::DataPath;
+use kdl::{KdlDocument, KdlEntry, KdlNode};
+use shiva::core::ImageAlignment;
+use std::str::FromStr;
+
+/// Entry point for compiling KDL report templates.
+#[derive(Debug)]
+pub struct Template;
+
+/// A parsed report template, ready to be rendered with different data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledTemplate {
+    pub title: Vec<BandElement>,
+    pub page_header: Vec<BandElement>,
+    pub column_header: Vec<Column>,
+    pub row: Vec<RowValue>,
+    pub groups: Vec<Group>,
+    pub column_footer: Vec<TextTemplate>,
+    pub page_footer: Vec<BandElement>,
+    pub summary: Vec<BandElement>,
+    pub variables: Vec<Variable>,
+    /// Database the rows are queried from instead of the `rows` of the data.
+    pub datasource: Option<SqliteSource>,
+    /// Where the rows, the params and further datasets are in JSON data.
+    pub data: DataLayout,
+    /// Fail rendering when a data row lacks a field referenced by `row` or `group`.
+    pub strict: bool,
+    /// Render a field or param path whose intermediate value is missing, such as
+    /// `$F(address.city)` without an `address`, as blank instead of failing.
+    pub blank_missing: bool,
+}
+
+/// A `value` of the `row` band. Every value produces a table cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowValue {
+    pub value: TextTemplate,
+    /// Text for a `null` value (`null_text`).
+    pub null_text: Option<String>,
+    /// Text for a field absent from the data row (`default_text`); falls back to `null_text`.
+    pub default_text: Option<String>,
+    /// Labels for boolean values (`true_text`, `false_text`); `true` and `false` by default.
+    pub true_text: Option<String>,
+    pub false_text: Option<String>,
+}
+
+/// An element of a free-form band (`title`, `page_header`, `page_footer`, `summary`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum BandElement {
+    Header {
+        level: u8,
+        text: TextTemplate,
+    },
+    /// An image looked up by `src` in the images map, then on disk.
+    Image {
+        src: String,
+        width: Option<String>,
+        height: Option<String>,
+        align: Option<String>,
+        alt: Option<String>,
+        title: Option<String>,
+    },
+    Text {
+        text: TextTemplate,
+        size: u8,
+    },
+    Paragraph {
+        elements: Vec<BandElement>,
+    },
+}
+
+/// A computed report variable, referenced as `$V{name}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub calc: Calculation,
+    pub field: Option<String>,
+    /// Name of the [`Dataset`] computed over, instead of the rows of the table.
+    pub dataset: Option<String>,
+}
+
+/// Aggregate function of a [`Variable`], computed over the data rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Calculation {
+    Sum,
+    Avg,
+    Count,
+    Min,
+    Max,
+}
+
+impl FromStr for Calculation {
+    type Err = ReportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sum" => Ok(Calculation::Sum),
+            "avg" => Ok(Calculation::Avg),
+            "count" => Ok(Calculation::Count),
+            "min" => Ok(Calculation::Min),
+            "max" => Ok(Calculation::Max),
+            _ => Err(Common(format!("Invalid 'calc': {}", s))),
+        }
+    }
+}
+
+/// A `group` section: rows sharing the same `by` field are framed by the
+/// group header and footer rows. Nested `group` nodes form inner levels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub field: String,
+    pub sort: bool,
+    pub header: Vec<TextTemplate>,
+    pub footer: Vec<TextTemplate>,
+}
+
+/// A `datasource type="sqlite"` node: the rows of the report are the result
+/// of `query`, run against the database at `path`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqliteSource {
+    /// Database file, looked up like an image `src`.
+    pub path: String,
+    /// A single `SELECT` statement. Parameters such as `:dept` are bound to
+    /// the params of the same name.
+    pub query: String,
+}
+
+/// The `data` node and `dataset` nodes: where the rows and params of the
+/// report are in JSON data, `/rows` and `/params` by default, and further
+/// named sets of rows variables can be computed over.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DataLayout {
+    pub rows: Option<DataPath>,
+    pub params: Option<DataPath>,
+    pub datasets: Vec<Dataset>,
+}
+
+/// A named set of rows, declared as `dataset name="orders" rows="$.result.orders"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dataset {
+    pub name: String,
+    pub rows: DataPath,
+}
+
+/// A table column declared in `column_header`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub width: f32,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<CompiledTemplate, ReportError> {
+        let doc: KdlDocument = template.parse()?;
+        let source = Source::new(template, &doc);
+        let template_node = doc
+            .get("template")
+            .ok_or_else(|| source.error(0, 0, "Missing 'template'"))?;
+        let strict = bool_property(template_node, "strict", &source)?;
+        let blank_missing = bool_property(template_node, "blank_missing", &source)?;
+        let template_elements = template_node
+            .children()
+            .ok_or_else(|| source.node_error(template_node, "Empty 'template'"))?;
+
+        // Every section is optional; an absent one is parsed as empty.
+        let empty = KdlDocument::new();
+        let band = |name: &str| -> Result<&KdlDocument, ReportError> {
+            Ok(source.section(template_elements, name)?.unwrap_or(&empty))
+        };
+        let title = parse_band(band("title")?, &source)?;
+        let page_header = parse_band(band("page_header")?, &source)?;
+        let column_header = parse_columns(band("column_header")?, &source)?;
+        let row = parse_row(band("row")?, &source)?;
+        let groups = parse_groups(template_elements, &source)?;
+        let column_footer = parse_values(band("column_footer")?, &source)?;
+        let page_footer = parse_band(band("page_footer")?, &source)?;
+        let summary = parse_band(band("summary")?, &source)?;
+        let data = parse_data_layout(template_elements, &source)?;
+        let variables = parse_variables(template_elements, &source)?;
+        for (node, variable) in template_elements
+            .nodes()
+            .iter()
+            .filter(|node| node.name().value() == "variable")
+            .zip(&variables)
+        {
+            check_dataset(node, variable, &data, &source)?;
+        }
+        let datasource = match template_elements.get("datasource") {
+            Some(node) => Some(parse_datasource(node, &source)?),
+            None => None,
+        };
+
+        Ok(CompiledTemplate {
+            title,
+            page_header,
+            column_header,
+            row,
+            groups,
+            column_footer,
+            page_footer,
+            summary,
+            variables,
+            datasource,
+            data,
+            strict,
+            blank_missing,
+        })
+    }
+}
+
+/// The template being parsed, used to locate errors.
+pub(crate) struct Source<'a> {
+    text: &'a str,
+    doc: &'a KdlDocument,
+}
+
+impl<'s> Source<'s> {
+    pub(crate) fn new(text: &'s str, doc: &'s KdlDocument) -> Self {
+        Source { text, doc }
+    }
+
+    /// Returns the children of the optional section `name`, or `None` if the
+    /// section is absent. A section without a `{ ... }` block is an error.
+    pub(crate) fn section<'a>(
+        &self,
+        template: &'a KdlDocument,
+        name: &str,
+    ) -> Result<Option<&'a KdlDocument>, ReportError> {
+        match template.get(name) {
+            Some(node) => node
+                .children()
+                .map(Some)
+                .ok_or_else(|| self.node_error(node, format!("Empty '{}'", name))),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the string value of the required property `name` of `node`.
+    pub(crate) fn string<'a>(&self, node: &'a KdlNode, name: &str) -> Result<&'a str, ReportError> {
+        let entry = node
+            .get(name)
+            .ok_or_else(|| self.node_error(node, format!("Missing '{}'", name)))?;
+        entry
+            .value()
+            .as_string()
+            .ok_or_else(|| self.entry_error(entry, format!("Invalid '{}'", name)))
+    }
+
+    /// Returns the integer value of the required property `name` of `node`.
+    pub(crate) fn integer(&self, node: &KdlNode, name: &str) -> Result<i64, ReportError> {
+        let entry = node
+            .get(name)
+            .ok_or_else(|| self.node_error(node, format!("Missing '{}'", name)))?;
+        entry
+            .value()
+            .as_i64()
+            .ok_or_else(|| self.entry_error(entry, format!("Invalid '{}'", name)))
+    }
+
+    pub(crate) fn node_error(&self, node: &KdlNode, message: impl Into<String>) -> ReportError {
+        self.error(node.span().offset(), node.span().len(), message)
+    }
+
+    pub(crate) fn entry_error(&self, entry: &KdlEntry, message: impl Into<String>) -> ReportError {
+        self.error(entry.span().offset(), entry.span().len(), message)
+    }
+
+    pub(crate) fn error(
+        &self,
+        offset: usize,
+        len: usize,
+        message: impl Into<String>,
+    ) -> ReportError {
+        InvalidTemplate {
+            message: message.into(),
+            location: self.locate(offset, len),
+        }
+    }
+
+    /// Locates `offset` in the source, with the names of the nodes enclosing it.
+    fn locate(&self, offset: usize, len: usize) -> SourceLocation {
+        let mut path = Vec::new();
+        let mut nodes = Some(self.doc);
+        while let Some(doc) = nodes.take() {
+            let node = doc.nodes().iter().find(|node| {
+                let span = node.span();
+                span.offset() <= offset && offset < span.offset() + span.len().max(1)
+            });
+            if let Some(node) = node {
+                path.push(node.name().value().to_string());
+                nodes = node.children();
+            }
+        }
+        SourceLocation::new(self.text, offset, len, path)
+    }
+
+    /// Compiles the string `text` of `entry`, locating syntax errors in the source.
+    pub(crate) fn compile_text(
+        &self,
+        entry: &KdlEntry,
+        text: &str,
+    ) -> Result<TextTemplate, ReportError> {
+        TextTemplate::parse(text).map_err(|error| {
+            let quote = entry
+                .value_repr()
+                .and_then(|repr| repr.find('"'))
+                .unwrap_or(0);
+            let offset = entry.span().offset() + quote + 1 + error.offset;
+            ExpressionParseError {
+                message: error.message,
+                location: self.locate(offset, 1),
+            }
+        })
+    }
+}
+
+fn parse_band(band: &KdlDocument, source: &Source) -> Result<Vec<BandElement>, ReportError> {
+    let mut elements = Vec::new();
+    for node in band.nodes() {
+        elements.extend(parse_element(node, source)?);
+    }
+    Ok(elements)
+}
+
+/// Parses a node of a free-form band. A `header` with several texts yields
+/// an element per text; unknown nodes yield none.
+pub(crate) fn parse_element(
+    node: &KdlNode,
+    source: &Source,
+) -> Result<Vec<BandElement>, ReportError> {
+    let element = match node.name().value() {
+        "header" => return parse_header(node, source),
+        "image" => parse_image(node, source)?,
+        "text" => parse_text(node, source)?,
+        "paragraph" => {
+            let children = node
+                .children()
+                .ok_or_else(|| source.node_error(node, "Missing children"))?;
+            let mut paragraph = Vec::new();
+            for child in children.nodes() {
+                if child.name().value() == "text" {
+                    paragraph.push(parse_text(child, source)?);
+                }
+            }
+            BandElement::Paragraph {
+                elements: paragraph,
+            }
+        }
+        _ => return Ok(Vec::new()),
+    };
+    Ok(vec![element])
+}
+
+fn parse_header(node: &KdlNode, source: &Source) -> Result<Vec<BandElement>, ReportError> {
+    let level = source.integer(node, "level")?;
+    if !(1..=6).contains(&level) {
+        let entry = node.get("level").expect("'level' was just read");
+        return Err(source.entry_error(entry, format!("Invalid 'level': {}", level)));
+    }
+    let mut headers = Vec::new();
+    for entry in node.entries() {
+        if entry.name().is_none() {
+            let text = entry
+                .value()
+                .as_string()
+                .ok_or_else(|| source.entry_error(entry, "Invalid text"))?;
+            headers.push(BandElement::Header {
+                level: level as u8,
+                text: source.compile_text(entry, text)?,
+            });
+        }
+    }
+    Ok(headers)
+}
+
+fn parse_image(node: &KdlNode, source: &Source) -> Result<BandElement, ReportError> {
+    let src = source.string(node, "src")?;
+    let dimension = |name: &str| -> Result<Option<String>, ReportError> {
+        let Some(entry) = node.get(name) else {
+            return Ok(None);
+        };
+        let value = entry.value();
+        match (value.as_i64(), value.as_string()) {
+            (Some(number), _) => Ok(Some(number.to_string())),
+            (_, Some(text)) => Ok(Some(text.to_string())),
+            _ => Err(source.entry_error(entry, format!("Invalid '{}'", name))),
+        }
+    };
+    let align = string_property(node, "align", source)?;
+    if let Some(align) = &align {
+        if ImageAlignment::from_str(align).is_err() {
+            let entry = node.get("align").expect("'align' was just read");
+            return Err(source.entry_error(entry, format!("Invalid 'align': {}", align)));
+        }
+    }
+    Ok(BandElement::Image {
+        src: src.to_string(),
+        width: dimension("width")?,
+        height: dimension("height")?,
+        align,
+        alt: string_property(node, "alt", source)?,
+        title: string_property(node, "title", source)?,
+    })
+}
+
+fn string_property(
+    node: &KdlNode,
+    name: &str,
+    source: &Source,
+) -> Result<Option<String>, ReportError> {
+    match node.get(name) {
+        Some(_) => Ok(Some(source.string(node, name)?.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// Reads the boolean `name` attribute of `node`, `false` if absent.
+fn bool_property(node: &KdlNode, name: &str, source: &Source) -> Result<bool, ReportError> {
+    match node.get(name) {
+        Some(entry) => entry
+            .value()
+            .as_bool()
+            .ok_or_else(|| source.entry_error(entry, format!("Invalid '{}'", name))),
+        None => Ok(false),
+    }
+}
+
+pub(crate) fn parse_text(node: &KdlNode, source: &Source) -> Result<BandElement, ReportError> {
+    let size = source.integer(node, "size")?;
+    let entry = node
+        .get(0)
+        .ok_or_else(|| source.node_error(node, "Missing text"))?;
+    let text = entry
+        .value()
+        .as_string()
+        .ok_or_else(|| source.entry_error(entry, "Invalid text"))?;
+    Ok(BandElement::Text {
+        text: source.compile_text(entry, text)?,
+        size: size as u8,
+    })
+}
+
+fn parse_columns(column_header: &KdlDocument, source: &Source) -> Result<Vec<Column>, ReportError> {
+    column_header
+        .nodes()
+        .iter()
+        .map(|column| parse_column(column, source))
+        .collect()
+}
+
+pub(crate) fn parse_column(column: &KdlNode, source: &Source) -> Result<Column, ReportError> {
+    let name = source.string(column, "name")?;
+    let entry = column
+        .get("width")
+        .ok_or_else(|| source.node_error(column, "Missing 'width'"))?;
+    let width = entry
+        .value()
+        .to_string()
+        .parse::<f32>()
+        .map_err(|_| source.entry_error(entry, "Invalid 'width'"))?;
+    Ok(Column {
+        name: name.to_string(),
+        width,
+    })
+}
+
+fn parse_values(band: &KdlDocument, source: &Source) -> Result<Vec<TextTemplate>, ReportError> {
+    band.nodes()
+        .iter()
+        .map(|node| parse_value(node, source))
+        .collect()
+}
+
+/// Parses a `value` node of the `row`, `column_footer` or group bands.
+fn parse_value(node: &KdlNode, source: &Source) -> Result<TextTemplate, ReportError> {
+    let entry = node
+        .entries()
+        .first()
+        .ok_or_else(|| source.node_error(node, "Missing 'value'"))?;
+    let value = entry
+        .value()
+        .as_string()
+        .ok_or_else(|| source.entry_error(entry, "Invalid 'value'"))?;
+    source.compile_text(entry, value)
+}
+
+fn parse_row(band: &KdlDocument, source: &Source) -> Result<Vec<RowValue>, ReportError> {
+    band.nodes()
+        .iter()
+        .map(|node| parse_row_value(node, source))
+        .collect()
+}
+
+pub(crate) fn parse_row_value(node: &KdlNode, source: &Source) -> Result<RowValue, ReportError> {
+    Ok(RowValue {
+        value: parse_value(node, source)?,
+        null_text: string_property(node, "null_text", source)?,
+        default_text: string_property(node, "default_text", source)?,
+        true_text: string_property(node, "true_text", source)?,
+        false_text: string_property(node, "false_text", source)?,
+    })
+}
+
+fn parse_variables(template: &KdlDocument, source: &Source) -> Result<Vec<Variable>, ReportError> {
+    template
+        .nodes()
+        .iter()
+        .filter(|node| node.name().value() == "variable")
+        .map(|node| parse_variable(node, source))
+        .collect()
+}
+
+pub(crate) fn parse_variable(node: &KdlNode, source: &Source) -> Result<Variable, ReportError> {
+    let name = source.string(node, "name")?;
+    let calc = source.string(node, "calc")?;
+    let calc = calc.parse::<Calculation>().map_err(|_| {
+        let entry = node.get("calc").expect("'calc' was just read");
+        source.entry_error(entry, format!("Invalid 'calc': {}", calc))
+    })?;
+    let field = match node.get("field") {
+        Some(_) => Some(source.string(node, "field")?.to_string()),
+        None if calc == Calculation::Count => None,
+        None => return Err(source.node_error(node, "Missing 'field'")),
+    };
+    Ok(Variable {
+        name: name.to_string(),
+        calc,
+        field,
+        dataset: string_property(node, "dataset", source)?,
+    })
+}
+
+/// Fails if `variable`, parsed from `node`, refers to an undeclared dataset.
+pub(crate) fn check_dataset(
+    node: &KdlNode,
+    variable: &Variable,
+    data: &DataLayout,
+    source: &Source,
+) -> Result<(), ReportError> {
+    match &variable.dataset {
+        Some(name) if !data.datasets.iter().any(|dataset| &dataset.name == name) => {
+            let entry = node.get("dataset").expect("'dataset' was parsed");
+            Err(source.entry_error(entry, format!("Unknown dataset '{}'", name)))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn parse_data_layout(
+    template: &KdlDocument,
+    source: &Source,
+) -> Result<DataLayout, ReportError> {
+    let mut layout = DataLayout::default();
+    if let Some(node) = template.get("data") {
+        layout.rows = path_property(node, "rows", source)?;
+        layout.params = path_property(node, "params", source)?;
+    }
+    for node in template
+        .nodes()
+        .iter()
+        .filter(|node| node.name().value() == "dataset")
+    {
+        let name = source.string(node, "name")?;
+        if layout.datasets.iter().any(|dataset| dataset.name == name) {
+            let entry = node.get("name").expect("'name' was just read");
+            return Err(source.entry_error(entry, format!("Duplicate dataset '{}'", name)));
+        }
+        let rows = path_property(node, "rows", source)?
+            .ok_or_else(|| source.node_error(node, "Missing 'rows'"))?;
+        layout.datasets.push(Dataset {
+            name: name.to_string(),
+            rows,
+        });
+    }
+    Ok(layout)
+}
+
+fn path_property(
+    node: &KdlNode,
+    name: &str,
+    source: &Source,
+) -> Result<Option<DataPath>, ReportError> {
+    let Some(path) = string_property(node, name, source)? else {
+        return Ok(None);
+    };
+    path.parse().map(Some).map_err(|message| {
+        let entry = node.get(name).expect("the property was just read");
+        source.entry_error(entry, format!("Invalid '{}': {}", name, message))
+    })
+}
+
+pub(crate) fn parse_datasource(
+    node: &KdlNode,
+    source: &Source,
+) -> Result<SqliteSource, ReportError> {
+    let kind = source.string(node, "type")?;
+    if kind != "sqlite" {
+        let entry = node.get("type").expect("'type' was just read");
+        return Err(source.entry_error(entry, format!("Unsupported 'type': {}", kind)));
+    }
+    let path = source.string(node, "path")?;
+    let children = node
+        .children()
+        .ok_or_else(|| source.node_error(node, "Empty 'datasource'"))?;
+    let query = children
+        .get("query")
+        .ok_or_else(|| source.node_error(node, "Missing 'query'"))?;
+    let entry = query
+        .entries()
+        .first()
+        .ok_or_else(|| source.node_error(query, "Missing 'query'"))?;
+    let sql = entry
+        .value()
+        .as_string()
+        .ok_or_else(|| source.entry_error(entry, "Invalid 'query'"))?;
+    Ok(SqliteSource {
+        path: path.to_string(),
+        query: sql.to_string(),
+    })
+}
+
+fn parse_groups(parent: &KdlDocument, source: &Source) -> Result<Vec<Group>, ReportError> {
+    let Some(node) = parent.get("group") else {
+        return Ok(Vec::new());
+    };
+    let mut groups = vec![parse_group(node, source)?];
+    if let Some(children) = node.children() {
+        groups.extend(parse_groups(children, source)?);
+    }
+    Ok(groups)
+}
+
+/// Parses a `group` node, without the groups nested in it.
+fn parse_group(node: &KdlNode, source: &Source) -> Result<Group, ReportError> {
+    let (field, sort) = parse_group_key(node, source)?;
+    let children = node
+        .children()
+        .ok_or_else(|| source.node_error(node, "Empty 'group'"))?;
+    let header = match source.section(children, "group_header")? {
+        Some(band) => parse_values(band, source)?,
+        None => Vec::new(),
+    };
+    let footer = match source.section(children, "group_footer")? {
+        Some(band) => parse_values(band, source)?,
+        None => Vec::new(),
+    };
+    Ok(Group {
+        field,
+        sort,
+        header,
+        footer,
+    })
+}
+
+/// Parses the `by` field and the `sort` flag of a `group` node.
+pub(crate) fn parse_group_key(
+    node: &KdlNode,
+    source: &Source,
+) -> Result<(String, bool), ReportError> {
+    let by = source.string(node, "by")?;
+    let field = by
+        .strip_prefix("$F(")
+        .and_then(|field| field.strip_suffix(')'))
+        .ok_or_else(|| {
+            let entry = node.get("by").expect("'by' was just read");
+            source.entry_error(entry, format!("Invalid 'by': {}", by))
+        })?;
+    let sort = bool_property(node, "sort", source)?;
+    Ok((field.to_string(), sort))
+}
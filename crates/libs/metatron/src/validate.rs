@@ -0,0 +1,324 @@
+use crate::error::ReportError;
+use crate::expr::TextTemplate;
+use crate::path::{parse_field_path, PathSegment};
+use crate::template::{
+    check_dataset, parse_column, parse_data_layout, parse_datasource, parse_element,
+    parse_group_key, parse_row_value, parse_text, parse_variable, BandElement, DataLayout, Source,
+};
+use kdl::{KdlDocument, KdlNode};
+use serde_json::Value as JValue;
+use std::collections::HashSet;
+
+/// Free-form bands of a template.
+const BANDS: [&str; 4] = ["title", "page_header", "page_footer", "summary"];
+
+/// Checks `template`, and its references against `data` if given, returning
+/// every problem found rather than stopping at the first one.
+pub(crate) fn validate(template: &str, data: Option<&str>) -> Vec<ReportError> {
+    let doc: KdlDocument = match template.parse() {
+        Ok(doc) => doc,
+        Err(error) => return vec![ReportError::KdlParseError(error)],
+    };
+    let mut validator = Validator {
+        source: Source::new(template, &doc),
+        data: None,
+        variables: HashSet::new(),
+        counts: Vec::new(),
+        problems: Vec::new(),
+    };
+    let mut json = None;
+    if let Some(data) = data {
+        match serde_json::from_str(data) {
+            Ok(data) => json = Some(data),
+            Err(error) => validator.problems.push(error.into()),
+        }
+    }
+    validator.validate_document(&doc, json.as_ref());
+    validator.problems
+}
+
+/// Param names and the union of row field names of the data.
+struct Data {
+    params: HashSet<String>,
+    /// `None` when the rows come from a `datasource` rather than the data.
+    fields: Option<HashSet<String>>,
+}
+
+/// Whether `path`, a name or a path such as `address.city`, starts at one of `names`.
+fn is_known(names: &HashSet<String>, path: &str) -> bool {
+    names.contains(path)
+        || matches!(
+            parse_field_path(path).as_deref(),
+            Ok([PathSegment::Key(name), ..]) if names.contains(name)
+        )
+}
+
+impl Data {
+    /// Reads the params and rows of `data` where `layout` says they are.
+    fn new(data: &JValue, layout: &DataLayout) -> Self {
+        let keys = |value: &JValue| -> Vec<String> {
+            value
+                .as_object()
+                .map(|object| object.keys().cloned().collect())
+                .unwrap_or_default()
+        };
+        let params = match &layout.params {
+            Some(path) => path.select(data).into_iter().flat_map(keys).collect(),
+            None => keys(&data["params"]).into_iter().collect(),
+        };
+        let rows: Vec<&JValue> = match &layout.rows {
+            Some(path) => match path.select(data).as_slice() {
+                [JValue::Array(rows)] => rows.iter().collect(),
+                values => values.to_vec(),
+            },
+            None => data["rows"].as_array().into_iter().flatten().collect(),
+        };
+        Data {
+            params,
+            fields: Some(rows.into_iter().flat_map(keys).collect()),
+        }
+    }
+}
+
+struct Validator<'a> {
+    source: Source<'a>,
+    data: Option<Data>,
+    /// Names of the declared variables.
+    variables: HashSet<String>,
+    /// Number of cells of each table band, to check they match.
+    counts: Vec<(&'a KdlNode, usize)>,
+    problems: Vec<ReportError>,
+}
+
+impl<'a> Validator<'a> {
+    fn validate_document(&mut self, doc: &'a KdlDocument, json: Option<&JValue>) {
+        for node in doc.nodes() {
+            if node.name().value() != "template" {
+                self.unknown(node);
+            }
+        }
+        let Some(template) = doc.get("template") else {
+            let error = self.source.error(0, 0, "Missing 'template'");
+            self.problems.push(error);
+            return;
+        };
+        for name in ["strict", "blank_missing"] {
+            if let Some(entry) = template.get(name) {
+                if entry.value().as_bool().is_none() {
+                    let message = format!("Invalid '{}'", name);
+                    self.problems.push(self.source.entry_error(entry, message));
+                }
+            }
+        }
+        let Some(sections) = self.children(template) else {
+            return;
+        };
+        let layout = self
+            .record(parse_data_layout(sections, &self.source))
+            .unwrap_or_default();
+        if let Some(json) = json {
+            self.data = Some(Data::new(json, &layout));
+        }
+        // Variables may be declared after they are used.
+        for node in sections.nodes() {
+            match node.name().value() {
+                "variable" => {
+                    if let Some(variable) = self.record(parse_variable(node, &self.source)) {
+                        self.record(check_dataset(node, &variable, &layout, &self.source));
+                        self.variables.insert(variable.name);
+                    }
+                }
+                "datasource" => {
+                    self.record(parse_datasource(node, &self.source));
+                    if let Some(data) = &mut self.data {
+                        data.fields = None;
+                    }
+                }
+                _ => {}
+            }
+        }
+        for node in sections.nodes() {
+            match node.name().value() {
+                "variable" | "datasource" | "data" | "dataset" => {}
+                name if BANDS.contains(&name) => self.validate_band(node),
+                "column_header" => self.validate_columns(node),
+                "row" => self.validate_values(node, true),
+                "column_footer" => self.validate_values(node, false),
+                "group" => self.validate_group(node),
+                _ => self.unknown(node),
+            }
+        }
+        self.validate_counts();
+    }
+
+    fn validate_band(&mut self, band: &'a KdlNode) {
+        let Some(children) = self.children(band) else {
+            return;
+        };
+        for node in children.nodes() {
+            match node.name().value() {
+                "paragraph" => {
+                    let Some(paragraph) = node.children() else {
+                        let error = self.source.node_error(node, "Missing children");
+                        self.problems.push(error);
+                        continue;
+                    };
+                    for child in paragraph.nodes() {
+                        if child.name().value() != "text" {
+                            self.unknown(child);
+                        } else if let Some(text) = self.record(parse_text(child, &self.source)) {
+                            self.check_element(child, &text);
+                        }
+                    }
+                }
+                "header" | "image" | "text" => {
+                    for element in self
+                        .record(parse_element(node, &self.source))
+                        .unwrap_or_default()
+                    {
+                        self.check_element(node, &element);
+                    }
+                }
+                _ => self.unknown(node),
+            }
+        }
+    }
+
+    fn check_element(&mut self, node: &KdlNode, element: &BandElement) {
+        if let BandElement::Header { text, .. } | BandElement::Text { text, .. } = element {
+            self.check_text(node, text, false);
+        }
+    }
+
+    fn validate_columns(&mut self, column_header: &'a KdlNode) {
+        let Some(children) = self.children(column_header) else {
+            return;
+        };
+        for node in children.nodes() {
+            if node.name().value() != "column" {
+                self.unknown(node);
+            } else {
+                self.record(parse_column(node, &self.source));
+            }
+        }
+        self.counts.push((column_header, children.nodes().len()));
+    }
+
+    /// Validates a band of `value` nodes; `row_scope` tells whether `$F(...)` may be used.
+    fn validate_values(&mut self, band: &'a KdlNode, row_scope: bool) {
+        let Some(children) = self.children(band) else {
+            return;
+        };
+        for node in children.nodes() {
+            if node.name().value() != "value" {
+                self.unknown(node);
+            } else if let Some(row_value) = self.record(parse_row_value(node, &self.source)) {
+                self.check_text(node, &row_value.value, row_scope);
+            }
+        }
+        self.counts.push((band, children.nodes().len()));
+    }
+
+    fn validate_group(&mut self, group: &'a KdlNode) {
+        if let Some((field, _)) = self.record(parse_group_key(group, &self.source)) {
+            self.check_field(group, &field);
+        }
+        let Some(children) = self.children(group) else {
+            return;
+        };
+        for node in children.nodes() {
+            match node.name().value() {
+                // Group bands refer to the first row of the group.
+                "group_header" | "group_footer" => self.validate_values(node, true),
+                "group" => self.validate_group(node),
+                _ => self.unknown(node),
+            }
+        }
+    }
+
+    /// Checks that the table bands have as many cells as the column header,
+    /// or as the row if there is no column header.
+    fn validate_counts(&mut self) {
+        let expected = self
+            .counts
+            .iter()
+            .find(|(node, _)| node.name().value() == "column_header")
+            .or_else(|| {
+                self.counts
+                    .iter()
+                    .find(|(node, _)| node.name().value() == "row")
+            })
+            .copied();
+        let Some((expected_node, expected)) = expected else {
+            return;
+        };
+        for (node, count) in &self.counts {
+            if *count != expected {
+                let error = self.source.node_error(
+                    node,
+                    format!(
+                        "'{}' has {} values but '{}' has {}",
+                        node.name().value(),
+                        count,
+                        expected_node.name().value(),
+                        expected
+                    ),
+                );
+                self.problems.push(error);
+            }
+        }
+    }
+
+    fn check_text(&mut self, node: &KdlNode, text: &TextTemplate, row_scope: bool) {
+        for variable in text.variables() {
+            if !self.variables.contains(variable) {
+                let message = format!("Unknown variable '$V{{{}}}'", variable);
+                self.problems.push(self.source.node_error(node, message));
+            }
+        }
+        if let Some(data) = &self.data {
+            for param in text.params() {
+                if !is_known(&data.params, param) {
+                    let message = format!("Unknown param '$P{{{}}}'", param);
+                    self.problems.push(self.source.node_error(node, message));
+                }
+            }
+        }
+        for field in text.fields() {
+            if row_scope {
+                self.check_field(node, field);
+            } else {
+                let message = format!("Field '$F({})' used outside of a row", field);
+                self.problems.push(self.source.node_error(node, message));
+            }
+        }
+    }
+
+    fn check_field(&mut self, node: &KdlNode, field: &str) {
+        if let Some(fields) = self.data.as_ref().and_then(|data| data.fields.as_ref()) {
+            if !is_known(fields, field) {
+                let message = format!("Unknown field '$F({})'", field);
+                self.problems.push(self.source.node_error(node, message));
+            }
+        }
+    }
+
+    /// Returns the `{ ... }` block of a section, recording an error if it has none.
+    fn children(&mut self, node: &'a KdlNode) -> Option<&'a KdlDocument> {
+        let children = node.children();
+        if children.is_none() {
+            let message = format!("Empty '{}'", node.name().value());
+            self.problems.push(self.source.node_error(node, message));
+        }
+        children
+    }
+
+    fn unknown(&mut self, node: &KdlNode) {
+        let message = format!("Unknown node '{}'", node.name().value());
+        self.problems.push(self.source.node_error(node, message));
+    }
+
+    fn record<T>(&mut self, result: Result<T, ReportError>) -> Option<T> {
+        result.map_err(|error| self.problems.push(error)).ok()
+    }
+}
@@ -1,5 +1,11 @@
-use metatron::Report;
-use shiva::core::TransformerTrait;
+use bytes::Bytes;
+use metatron::{
+    BandElement, CsvOptions, CsvSource, DataPath, DenyAllResolver, DirectoryResolver, Expr,
+    JsonArraySource, MemoryResolver, NdjsonSource, OutputFormat, Report, ReportError,
+    ResourceResolver, Template, TextPart, TextTemplate,
+};
+use serde_json::json;
+use shiva::core::{Element, ImageAlignment, TransformerTrait};
 use std::collections::HashMap;
 use tracing::info;
 
@@ -13,7 +19,7 @@ fn template_data() -> Result<(String, String), anyhow::Error> {
 fn test_generate() -> anyhow::Result<()> {
     let (template, data) = template_data()?;
     let images = HashMap::new();
-    let result = Report::generate(&template, &data, &images, "pdf");
+    let result = Report::generate(&template, &data, &images, OutputFormat::Pdf);
     assert!(result.is_ok());
     std::fs::write("../../../data/report_generate.pdf", result.unwrap())?;
     Ok(())
@@ -41,8 +47,1110 @@ fn test_generate_by_shiva() -> anyhow::Result<()> {
 fn test_to_u8arr() -> anyhow::Result<()> {
     let (template, data) = template_data()?;
     let images = HashMap::new();
-    let result = Report::generate(&template, &data, &images, "pdf");
+    let result = Report::generate(&template, &data, &images, OutputFormat::Pdf);
     let doc = result?;
     assert_eq!(doc.len(), 499343);
     Ok(())
 }
+
+#[test]
+fn test_compile_once_render_many() -> anyhow::Result<()> {
+    let (template, data) = template_data()?;
+    let images = HashMap::new();
+    let compiled = Template::parse(&template)?;
+    assert_eq!(compiled.column_header.len(), 3);
+    assert_eq!(
+        compiled.row[0].value.as_expr(),
+        Some(&Expr::Field("name".to_string()))
+    );
+    assert_eq!(
+        compiled.title[1],
+        BandElement::Header {
+            level: 1,
+            text: TextTemplate {
+                parts: vec![
+                    TextPart::Expr(Expr::Param("company_name".to_string())),
+                    TextPart::Literal(" Employee Report".to_string())
+                ]
+            }
+        }
+    );
+    let first = compiled.render(&data, &images, OutputFormat::Text)?;
+    let second = compiled.render(&data, &images, OutputFormat::Text)?;
+    assert_eq!(first, second);
+    assert_eq!(
+        first,
+        Report::generate(&template, &data, &images, OutputFormat::Text)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_variables() -> anyhow::Result<()> {
+    let (_, data) = template_data()?;
+    let template = r#"
+template {
+    variable name="total" calc="sum" field="salary"
+    variable name="average" calc="avg" field="age"
+    variable name="people" calc="count"
+    variable name="youngest" calc="min" field="age"
+    variable name="oldest" calc="max" field="age"
+    title {
+        header level=1 "$V{people} people, $V{youngest}-$V{oldest} years"
+    }
+    page_header {
+        text size=7 "Header"
+    }
+    column_header {
+        column name="Name" width=30
+    }
+    row {
+        value "$F(name)"
+    }
+    column_footer {
+        value "$V{total} / $V{average}"
+    }
+    page_footer {
+        text size=7 "Footer"
+    }
+    summary {
+        paragraph {
+            text size=10 "Total: $V{total}"
+        }
+    }
+}
+"#;
+    let result = Report::generate(template, &data, &HashMap::new(), OutputFormat::Text)?;
+    let text = String::from_utf8(result.to_vec())?;
+    assert!(text.contains("3 people, 25-35 years"));
+    assert!(text.contains("180000 / 30"));
+    assert!(text.contains("Total: 180000"));
+    Ok(())
+}
+
+#[test]
+fn test_variable_invalid_calc() -> anyhow::Result<()> {
+    let (template, _) = template_data()?;
+    let template = template.replace("calc=\"avg\"", "calc=\"median\"");
+    assert!(Template::parse(&template).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_groups() -> anyhow::Result<()> {
+    let template = r#"
+template {
+    variable name="total" calc="sum" field="salary"
+    variable name="people" calc="count"
+    title {
+        header level=1 "Staff"
+    }
+    page_header {
+        text size=7 "Header"
+    }
+    column_header {
+        column name="Name" width=30
+        column name="Salary" width=20
+    }
+    group by="$F(department)" sort=true {
+        group_header {
+            value "Department: $F(department)"
+            value ""
+        }
+        group_footer {
+            value "$V{people} people"
+            value "$V{total}"
+        }
+    }
+    row {
+        value "$F(name)"
+        value "$F(salary)"
+    }
+    column_footer {
+        value "Total"
+        value "$V{total}"
+    }
+    page_footer {
+        text size=7 "Footer"
+    }
+    summary {
+        paragraph {
+            text size=10 "End"
+        }
+    }
+}
+"#;
+    let data = r#"{
+        "rows": [
+            {"name": "John", "department": "Sales", "salary": 50000},
+            {"name": "Jane", "department": "IT", "salary": 60000},
+            {"name": "Jim", "department": "Sales", "salary": 70000}
+        ],
+        "params": {}
+    }"#;
+    let doc = Report::to_document(template, data, &HashMap::new())?;
+    let rows = doc
+        .elements
+        .iter()
+        .find_map(|element| match element {
+            Element::Table { rows, .. } => Some(rows),
+            _ => None,
+        })
+        .unwrap();
+    let first_cells: Vec<String> = rows
+        .iter()
+        .map(|row| match &row.cells[0].element {
+            Element::Text { text, .. } => text.clone(),
+            _ => String::new(),
+        })
+        .collect();
+    assert_eq!(
+        first_cells,
+        vec![
+            "Department: IT",
+            "Jane",
+            "1 people",
+            "Department: Sales",
+            "John",
+            "Jim",
+            "2 people",
+            "Total",
+        ]
+    );
+    match &rows[6].cells[1].element {
+        Element::Text { text, .. } => assert_eq!(text, "120000"),
+        _ => panic!("expected text"),
+    }
+    Ok(())
+}
+
+fn minimal_template(row: &str, footer: &str) -> String {
+    format!(
+        r#"
+template {{
+    variable name="total" calc="sum" field="salary"
+    title {{
+        header level=1 "Staff"
+    }}
+    page_header {{
+        text size=7 "Header"
+    }}
+    column_header {{
+        column name="Value" width=30
+    }}
+    row {{
+        value "{}"
+    }}
+    column_footer {{
+        value "{}"
+    }}
+    page_footer {{
+        text size=7 "Footer"
+    }}
+    summary {{
+        paragraph {{
+            text size=10 "End"
+        }}
+    }}
+}}
+"#,
+        row, footer
+    )
+}
+
+#[test]
+fn test_expressions() -> anyhow::Result<()> {
+    let (_, data) = template_data()?;
+    let template = minimal_template(
+        "${ upper($F(name)) + ': ' + $F(salary) / 1000 + 'k' }",
+        "${ if($V{total} > 100000, 'High', 'Low') } ${ round($V{total} / 7, 2) } ${ $P{company_name} == 'ABCDFG Ltd' && !false }",
+    );
+    let result = Report::generate(&template, &data, &HashMap::new(), OutputFormat::Text)?;
+    let text = String::from_utf8(result.to_vec())?;
+    assert!(text.contains("JOHN: 50k"));
+    assert!(text.contains("JIM: 70k"));
+    assert!(text.contains("High 25714.29 true"));
+    Ok(())
+}
+
+#[test]
+fn test_expression_parse_error_location() {
+    let template = minimal_template("$F(name)", "${ $V{total} * }");
+    match Template::parse(&template) {
+        Err(ReportError::ExpressionParseError { location, .. }) => {
+            assert_eq!(location.line, 17);
+            assert_eq!(location.column, 31);
+            assert_eq!(location.path, ["template", "column_footer", "value"]);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    let template = minimal_template("${ unknown($F(name)) }", "");
+    assert!(matches!(
+        Template::parse(&template),
+        Err(ReportError::ExpressionParseError { location, .. }) if location.line == 14
+    ));
+}
+
+#[test]
+fn test_row_text_interpolation() -> anyhow::Result<()> {
+    let (_, data) = template_data()?;
+    let template = minimal_template(
+        "$F(name) (Age: $F(age)) at $P{company_name}, ${ $F(salary) / 1000 }k",
+        "",
+    );
+    let result = Report::generate(&template, &data, &HashMap::new(), OutputFormat::Text)?;
+    let text = String::from_utf8(result.to_vec())?;
+    assert!(text.contains("John (Age: 25) at ABCDFG Ltd, 50k"));
+    assert!(text.contains("Jane (Age: 30) at ABCDFG Ltd, 60k"));
+    Ok(())
+}
+
+fn table_rows(doc: &shiva::core::Document) -> Vec<Vec<String>> {
+    doc.elements
+        .iter()
+        .find_map(|element| match element {
+            Element::Table { rows, .. } => Some(rows),
+            _ => None,
+        })
+        .unwrap()
+        .iter()
+        .map(|row| {
+            row.cells
+                .iter()
+                .map(|cell| match &cell.element {
+                    Element::Text { text, .. } => text.clone(),
+                    _ => String::new(),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn test_cells_stay_aligned() -> anyhow::Result<()> {
+    let template = r#"
+template {
+    title {
+        header level=1 "Staff"
+    }
+    page_header {
+        text size=7 "Header"
+    }
+    column_header {
+        column name="Name" width=30
+        column name="Age" width=10
+        column name="Active" width=10
+        column name="Tags" width=20
+        column name="Salary" width=20
+    }
+    row {
+        value "$F(name)"
+        value "$F(age)" null_text="-" default_text="n/a"
+        value "$F(active)" true_text="Yes" false_text="No"
+        value "$F(tags)"
+        value "$F(salary)"
+    }
+    column_footer {
+        value ""
+    }
+    page_footer {
+        text size=7 "Footer"
+    }
+    summary {
+        paragraph {
+            text size=10 "End"
+        }
+    }
+}
+"#;
+    let data = r#"{
+        "rows": [
+            {"name": "John", "age": null, "active": true, "tags": ["a", "b"], "salary": 50000},
+            {"name": "Jane", "active": false, "tags": [], "salary": 60000}
+        ],
+        "params": {}
+    }"#;
+    let doc = Report::to_document(template, data, &HashMap::new())?;
+    let rows = table_rows(&doc);
+    assert_eq!(rows[0], vec!["John", "-", "Yes", "a, b", "50000"]);
+    assert_eq!(rows[1], vec!["Jane", "n/a", "No", " ", "60000"]);
+
+    let strict = template.replace("template {", "template strict=true {");
+    match Report::to_document(&strict, data, &HashMap::new()) {
+        Err(ReportError::MissingField { row, field }) => {
+            assert_eq!(row, 1);
+            assert_eq!(field, "age");
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_images_map() -> anyhow::Result<()> {
+    let (template, data) = template_data()?;
+    let template = template.replace(
+        r#"image src="../../../logo.png" width=100 height=100"#,
+        r#"image src="company-logo.png" width=100 height="50%" align="center" alt="Logo" title="logo""#,
+    );
+    let mut images = HashMap::new();
+    images.insert(
+        "company-logo.png".to_string(),
+        Bytes::from(std::fs::read("../../../logo.png")?),
+    );
+    let doc = Report::to_document(&template, &data, &images)?;
+    let Element::Image(image) = &doc.elements[0] else {
+        panic!("expected image");
+    };
+    assert_eq!(image.bytes(), &images["company-logo.png"]);
+    assert_eq!(image.size().width.as_deref(), Some("100"));
+    assert_eq!(image.size().height.as_deref(), Some("50%"));
+    assert_eq!(image.align(), &ImageAlignment::Center);
+    assert_eq!(image.alt(), "Logo");
+    assert_eq!(image.title(), "logo");
+
+    let result = Report::to_document(&template, &data, &HashMap::new());
+    assert!(matches!(result, Err(ReportError::ImageNotFound(src)) if src == "company-logo.png"));
+    Ok(())
+}
+
+#[test]
+fn test_resource_resolvers() -> anyhow::Result<()> {
+    let resolver = DirectoryResolver::new("../../../data");
+    assert!(resolver.resolve("logo.png")?.is_some());
+    assert!(resolver.resolve("./logo.png")?.is_some());
+    assert!(resolver.resolve("missing.png")?.is_none());
+    for name in ["", ".", "./"] {
+        assert!(resolver.resolve(name)?.is_none(), "{:?}", name);
+    }
+    for name in ["../logo.png", "/etc/passwd", "data/../../logo.png"] {
+        assert!(matches!(
+            resolver.resolve(name),
+            Err(ReportError::ResourceAccessDenied(_))
+        ));
+    }
+    assert!(matches!(
+        DenyAllResolver.resolve("logo.png"),
+        Err(ReportError::ResourceAccessDenied(_))
+    ));
+    let (template, data) = template_data()?;
+    let directory = template.replace("../../../logo.png", ".");
+    let error = Template::parse(&directory)?
+        .render_with_resolver(&data, &resolver, OutputFormat::Html)
+        .unwrap_err();
+    assert_eq!(error.code(), "missing_resource");
+
+    let (template, data) = template_data()?;
+    let template = template.replace("../../../logo.png", "logo.png");
+    let mut images = HashMap::new();
+    images.insert("logo.png".to_string(), Bytes::from_static(b"png"));
+    let doc = Template::parse(&template)?
+        .to_document_with_resolver(&data, &MemoryResolver::new(images))?;
+    let Element::Image(image) = &doc.elements[0] else {
+        panic!("expected image");
+    };
+    assert_eq!(image.bytes(), &Bytes::from_static(b"png"));
+    let result =
+        Report::generate_with_resolver(&template, &data, &DenyAllResolver, OutputFormat::Text);
+    assert!(result.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_optional_sections() -> anyhow::Result<()> {
+    let (_, data) = template_data()?;
+    let list = r#"
+template {
+    row {
+        value "$F(name)"
+    }
+}
+"#;
+    let doc = Template::parse(list)?.to_document(&data, &HashMap::new())?;
+    assert_eq!(
+        table_rows(&doc),
+        vec![vec!["John"], vec!["Jane"], vec!["Jim"]]
+    );
+    assert!(doc.page_header.is_empty() && doc.page_footer.is_empty());
+
+    let letter = r#"
+template {
+    title {
+        header level=1 "$P{company_name}"
+    }
+}
+"#;
+    let doc = Template::parse(letter)?.to_document(&data, &HashMap::new())?;
+    assert_eq!(doc.elements.len(), 1);
+    assert!(matches!(&doc.elements[0], Element::Header { text, .. } if text == "ABCDFG Ltd"));
+    Report::generate(letter, &data, &HashMap::new(), OutputFormat::Pdf)?;
+
+    let result = Template::parse("template {\n    summary\n}");
+    assert!(
+        matches!(result, Err(ReportError::InvalidTemplate { message, .. }) if message == "Empty 'summary'")
+    );
+    let result = Template::parse("template {\n    summary {\n        text \"No size\"\n    }\n}");
+    assert!(
+        matches!(result, Err(ReportError::InvalidTemplate { message, .. }) if message == "Missing 'size'")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_template_error_locations() -> anyhow::Result<()> {
+    let template = minimal_template("$F(name)", "").replace("header level=1", "header");
+    let error = Template::parse(&template).unwrap_err();
+    let Some(location) = error.location() else {
+        panic!("unexpected error: {:?}", error);
+    };
+    assert_eq!(location.path, ["template", "title", "header"]);
+    assert_eq!((location.line, location.column), (5, 9));
+    assert_eq!(error.code(), "invalid_template");
+    assert_eq!(
+        error.render_diagnostic(&template),
+        "error: Missing 'level'\n --> line 5, column 9 (template > title > header)\n  |\n5 |         header \"Staff\"\n  |         ^^^^^^^^^^^^^^\n"
+    );
+
+    let template = minimal_template("$F(name)", "").replace("calc=\"sum\"", "calc=\"median\"");
+    let error = Template::parse(&template).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Template error at line 3, column 27 (template > variable): Invalid 'calc': median"
+    );
+
+    let error = Template::parse("template {\n    title {\n}").unwrap_err();
+    assert!(matches!(error, ReportError::KdlParseError(_)));
+    assert_eq!(error.code(), "template_parse_error");
+    assert!(error
+        .render_diagnostic("template {\n    title {\n}")
+        .contains(" --> line "));
+
+    let (_, data) = template_data()?;
+    let template = minimal_template("${ $F(salary) / ($F(age) - 30) }", "");
+    let result = Template::parse(&template)?.to_document(&data, &HashMap::new());
+    match result {
+        Err(ReportError::DataRow { row, source }) => {
+            assert_eq!(row, 1);
+            assert!(matches!(*source, ReportError::ExpressionEvalError(_)));
+            assert_eq!(source.code(), "expression_eval_error");
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_validate() -> anyhow::Result<()> {
+    let (template, data) = template_data()?;
+    assert!(Report::validate(&template).is_empty());
+    assert!(Report::validate_with_data(&template, &data).is_empty());
+
+    let template = r#"
+template {
+    title {
+        header level=7 "$F(name)"
+        banner "Staff"
+    }
+    column_header {
+        column name="Name" width="wide"
+        column name="Salary" width=20
+    }
+    row {
+        value "$F(name)"
+        value "$F(salary)" null_text=0
+        value "$F(bonus)"
+    }
+    column_footer {
+        value "$V{total}"
+        value "$P{currency}"
+    }
+}
+"#;
+    let messages = |problems: Vec<ReportError>| -> Vec<String> {
+        problems
+            .iter()
+            .map(|problem| match problem {
+                ReportError::InvalidTemplate { message, location } => {
+                    format!("{}: {}", location.line, message)
+                }
+                problem => panic!("unexpected problem: {:?}", problem),
+            })
+            .collect()
+    };
+    assert_eq!(
+        messages(Report::validate(template)),
+        vec![
+            "4: Invalid 'level': 7",
+            "5: Unknown node 'banner'",
+            "8: Invalid 'width'",
+            "13: Invalid 'null_text'",
+            "17: Unknown variable '$V{total}'",
+            "11: 'row' has 3 values but 'column_header' has 2",
+        ]
+    );
+    assert_eq!(
+        messages(Report::validate_with_data(template, &data)),
+        vec![
+            "4: Invalid 'level': 7",
+            "5: Unknown node 'banner'",
+            "8: Invalid 'width'",
+            "13: Invalid 'null_text'",
+            "14: Unknown field '$F(bonus)'",
+            "17: Unknown variable '$V{total}'",
+            "18: Unknown param '$P{currency}'",
+            "11: 'row' has 3 values but 'column_header' has 2",
+        ]
+    );
+
+    let template = "template {\n    title {\n        text size=7 \"$F(name)\"\n    }\n}";
+    assert_eq!(
+        messages(Report::validate(template)),
+        vec!["3: Field '$F(name)' used outside of a row"]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_output_formats() -> anyhow::Result<()> {
+    for format in Report::supported_formats() {
+        assert_eq!(format.to_string().parse::<OutputFormat>()?, *format);
+        assert_eq!(format.extension().parse::<OutputFormat>()?, *format);
+    }
+    assert_eq!("PDF".parse::<OutputFormat>()?, OutputFormat::Pdf);
+    assert_eq!(OutputFormat::Text.to_string(), "text");
+    assert_eq!(OutputFormat::Text.extension(), "txt");
+    assert_eq!(OutputFormat::Html.mime_type(), "text/html");
+    assert!(matches!(
+        "xls".parse::<OutputFormat>(),
+        Err(ReportError::InvalidDocumentType(name)) if name == "xls"
+    ));
+
+    let (template, data) = template_data()?;
+    let result = Report::generate(&template, &data, &HashMap::new(), OutputFormat::Html)?;
+    assert!(String::from_utf8(result.to_vec())?.contains("ABCDFG Ltd Employee Report"));
+
+    // Every advertised format can be generated from the sample report.
+    for format in Report::supported_formats() {
+        let result = Report::generate(&template, &data, &HashMap::new(), *format)
+            .map_err(|e| anyhow::anyhow!("{}: {}", format, e))?;
+        assert!(!result.is_empty(), "{}", format);
+        if format.is_text() {
+            assert!(std::str::from_utf8(&result).is_ok(), "{}", format);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_scaffold() -> anyhow::Result<()> {
+    let (_, data) = template_data()?;
+    let template = Report::scaffold(&data)?;
+    assert_eq!(
+        template,
+        r#"template {
+    variable name="average_age" calc="avg" field="age"
+    variable name="average_salary" calc="avg" field="salary"
+    variable name="row_count" calc="count"
+    title {
+        header level=1 "Report"
+    }
+    column_header {
+        column name="Name" width=8
+        column name="Age" width=8
+        column name="Salary" width=8
+    }
+    row {
+        value "$F(name)"
+        value "$F(age)"
+        value "$F(salary)"
+    }
+    column_footer {
+        value "Average:"
+        value "$V{average_age}"
+        value "$V{average_salary}"
+    }
+    page_footer {
+        text size=7 "Generated with Metatron"
+    }
+    summary {
+        paragraph {
+            text size=10 "Rows: $V{row_count}"
+            text size=10 "Company Name: $P{company_name}"
+            text size=10 "Company Address: $P{company_address}"
+        }
+    }
+}
+"#
+    );
+    assert!(Report::validate_with_data(&template, &data).is_empty());
+    let text = Report::generate(&template, &data, &HashMap::new(), OutputFormat::Text)?;
+    let text = String::from_utf8(text.to_vec())?;
+    assert!(text.contains("60000"));
+    assert!(text.contains("Rows: 3"));
+
+    let data = r#"{"rows": [
+        {"id": 1, "description": "A rather long description of the first item", "address": {"city": "Paris"}},
+        {"id": null, "description": null, "in stock": true, "note \"x\"": "y"}
+    ], "params": {}}"#;
+    let template = Report::scaffold(data)?;
+    assert!(template.contains("column name=\"Description\" width=40"));
+    assert!(template.contains("column name=\"In Stock\" width=8"));
+    assert!(template.contains("value \"$F(note \\\"x\\\")\""));
+    assert!(template.contains("// Field \"address\" is not shown"));
+    assert!(Report::validate_with_data(&template, data).is_empty());
+    // The first column is null in the second row, which still counts.
+    let text = Report::generate(&template, data, &HashMap::new(), OutputFormat::Text)?;
+    assert!(String::from_utf8(text.to_vec())?.contains("Rows: 2"));
+    assert!(matches!(
+        Report::scaffold("[1, 2]"),
+        Err(ReportError::InvalidData(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_csv_data() -> anyhow::Result<()> {
+    let template = minimal_template(
+        "$F(name) is ${ $F(age) + 1 } next year",
+        "$V{total} $P{company_name}",
+    );
+    // "Zoë" in windows-1252, with a quoted field holding the delimiter.
+    let mut csv = b"name;age;salary\nZo\xeb;41;50000.5\n'Smith; John';29;\n".to_vec();
+    csv.extend_from_slice(b"Jim;007;70000\n");
+    let options = CsvOptions {
+        delimiter: b';',
+        quote: b'\'',
+        encoding: "windows-1252".to_string(),
+        infer_types: true,
+    };
+    let params = r#"{"company_name": "ABCDFG Ltd"}"#;
+    let result = Report::generate_from_csv(
+        &template,
+        &csv,
+        Some(params),
+        &options,
+        &HashMap::new(),
+        OutputFormat::Text,
+    )?;
+    let text = String::from_utf8(result.to_vec())?;
+    assert!(text.contains("Zoë is 42 next year"));
+    assert!(text.contains("Smith; John is 30 next year"));
+    assert!(text.contains("120000.5 ABCDFG Ltd"));
+
+    // Without type inference, `007` stays text and concatenates.
+    let options = CsvOptions {
+        infer_types: false,
+        ..options
+    };
+    let result = Report::generate_from_csv(
+        &template,
+        &csv,
+        Some(params),
+        &options,
+        &HashMap::new(),
+        OutputFormat::Text,
+    )?;
+    let text = String::from_utf8(result.to_vec())?;
+    assert!(text.contains("Jim is 0071 next year"));
+
+    let error = Report::generate_from_csv(
+        &template,
+        b"name,age\nJohn\n",
+        None,
+        &CsvOptions::default(),
+        &HashMap::new(),
+        OutputFormat::Text,
+    )
+    .unwrap_err();
+    assert!(matches!(error, ReportError::CsvParseError(_)));
+    assert_eq!(error.code(), "data_parse_error");
+    let error = Report::generate_from_csv(
+        &template,
+        b"name\n",
+        None,
+        &CsvOptions {
+            encoding: "klingon".to_string(),
+            ..CsvOptions::default()
+        },
+        &HashMap::new(),
+        OutputFormat::Text,
+    )
+    .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Invalid option: Unknown encoding: klingon"
+    );
+    assert_eq!(error.code(), "invalid_option");
+    let error = Report::generate_from_csv(
+        &template,
+        b"name\n",
+        Some("[1]"),
+        &CsvOptions::default(),
+        &HashMap::new(),
+        OutputFormat::Text,
+    )
+    .unwrap_err();
+    assert_eq!(error.code(), "data_error");
+    Ok(())
+}
+
+#[test]
+fn test_sqlite_datasource() -> anyhow::Result<()> {
+    let connection = rusqlite::Connection::open_in_memory()?;
+    connection.execute_batch(
+        "CREATE TABLE staff (name TEXT, age INTEGER, salary REAL, dept TEXT);
+         INSERT INTO staff VALUES ('John', 25, 50000.5, 'sales'), ('Jane', 30, 60000, 'it'),
+                                  ('Jim', 35, NULL, 'sales');",
+    )?;
+    let database = Bytes::from(connection.serialize(rusqlite::MAIN_DB)?.to_vec());
+    let images = HashMap::from([("staff.db".to_string(), database)]);
+
+    let template = minimal_template("$F(name) $F(age)", "$V{total} $P{company_name}").replace(
+        "template {\n",
+        "template {\n    datasource type=\"sqlite\" path=\"staff.db\" {\n        query \"SELECT name, age, salary FROM staff WHERE dept = :dept ORDER BY age\"\n    }\n",
+    );
+    let data = r#"{"params": {"company_name": "ABCDFG Ltd", "dept": "sales"}}"#;
+    let text = String::from_utf8(
+        Report::generate(&template, data, &images, OutputFormat::Text)?.to_vec(),
+    )?;
+    assert!(text.contains("John 25"));
+    assert!(text.contains("Jim 35"));
+    assert!(!text.contains("Jane"));
+    assert!(text.contains("50000.5 ABCDFG Ltd"));
+    assert!(Report::validate_with_data(&template, data).is_empty());
+
+    // Datasets of JSON data still apply when the rows are queried.
+    let with_dataset = template.replace(
+        "template {\n",
+        "template {\n    dataset name=\"orders\" rows=\"/orders\"\n    variable name=\"orders\" calc=\"sum\" field=\"amount\" dataset=\"orders\"\n",
+    ).replace("$P{company_name}", "$P{company_name} $V{orders}");
+    let orders = r#"{"params": {"company_name": "ABCDFG Ltd", "dept": "sales"}, "orders": [{"amount": 2}, {"amount": 3}]}"#;
+    let text = String::from_utf8(
+        Report::generate(&with_dataset, orders, &images, OutputFormat::Text)?.to_vec(),
+    )?;
+    assert!(text.contains("50000.5 ABCDFG Ltd 5"));
+
+    let error = Report::generate(
+        &template,
+        r#"{"params": {"company_name": "ABCDFG Ltd"}}"#,
+        &images,
+        OutputFormat::Text,
+    )
+    .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Data source error: Missing param 'dept' for the query"
+    );
+    assert_eq!(error.code(), "data_source_error");
+
+    let error = Report::generate(&template, data, &HashMap::new(), OutputFormat::Text).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Data source error: Database not found: staff.db"
+    );
+
+    let writing = template.replace(
+        "SELECT name, age, salary FROM staff WHERE dept = :dept ORDER BY age",
+        "DELETE FROM staff",
+    );
+    let error = Report::generate(&writing, data, &images, OutputFormat::Text).unwrap_err();
+    assert!(matches!(error, ReportError::DataSourceError(_)));
+
+    let attaching = template.replace(
+        "SELECT name, age, salary FROM staff WHERE dept = :dept ORDER BY age",
+        "ATTACH 'other.db' AS other",
+    );
+    let error = Report::generate(&attaching, data, &images, OutputFormat::Text).unwrap_err();
+    assert!(matches!(error, ReportError::DataSourceError(_)));
+
+    let error = Template::parse(&template.replace("\"sqlite\"", "\"oracle\"")).unwrap_err();
+    assert_eq!(error.code(), "invalid_template");
+    assert!(error.to_string().contains("Unsupported 'type': oracle"));
+    Ok(())
+}
+
+/// A reader handing out one byte per read.
+struct Trickle<'a>(&'a [u8]);
+
+impl std::io::Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.0.len()).min(1);
+        buf[..len].copy_from_slice(&self.0[..len]);
+        self.0 = &self.0[len..];
+        Ok(len)
+    }
+}
+
+#[test]
+fn test_streaming_sources() -> anyhow::Result<()> {
+    let json = r#" [ {"name": "a,]}\"b", "tags": ["x", {"k": "]"}]}, 7 , "s\\",
+        -1.5e3, null, {"name": "Zoë"} ] "#;
+    let rows = JsonArraySource::new(json.as_bytes()).collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(rows, serde_json::from_str::<Vec<serde_json::Value>>(json)?);
+    // Elements split across reads, one byte at a time.
+    let trickled = JsonArraySource::new(Trickle(json.as_bytes()));
+    assert_eq!(trickled.collect::<Result<Vec<_>, _>>()?, rows);
+    assert!(JsonArraySource::new(&b"[]"[..]).next().is_none());
+    for invalid in [r#"{"rows": []}"#, "[1 2]", "[1,", "[{]"] {
+        let result = JsonArraySource::new(invalid.as_bytes()).collect::<Result<Vec<_>, _>>();
+        assert!(result.is_err(), "{}", invalid);
+    }
+
+    let ndjson = "{\"name\": \"John\"}\n\n{\"name\": \"Jane\"}\r\n";
+    let rows = NdjsonSource::new(ndjson.as_bytes()).collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(rows, vec![json!({"name": "John"}), json!({"name": "Jane"})]);
+    let mut rows = NdjsonSource::new("{\"name\": \"John\"}\nnot json\n{}\n".as_bytes());
+    assert!(rows.next().unwrap().is_ok());
+    assert!(matches!(
+        rows.next().unwrap(),
+        Err(ReportError::JsonParseError(_))
+    ));
+    assert!(rows.next().is_none());
+
+    let options = CsvOptions {
+        infer_types: true,
+        ..CsvOptions::default()
+    };
+    let rows = CsvSource::new(&b"name,age\nJohn,25\nJane,\n"[..], &options)?
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(
+        rows,
+        vec![
+            json!({"name": "John", "age": 25}),
+            json!({"name": "Jane", "age": null})
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_streaming_groups() -> anyhow::Result<()> {
+    let template = r#"
+template {
+    variable name="total" calc="sum" field="salary"
+    variable name="people" calc="count"
+    variable name="top" calc="max" field="salary"
+    title {
+        header level=1 "$V{people} people"
+    }
+    column_header {
+        column name="Name" width=30
+        column name="Salary" width=20
+    }
+    group by="$F(department)" {
+        group_header {
+            value "Department $F(department): $V{people} people"
+            value "$V{total}"
+        }
+        group by="$F(team)" {
+            group_footer {
+                value "Team $F(team)"
+                value "$V{top}"
+            }
+        }
+    }
+    row {
+        value "$F(name)"
+        value "$F(salary)"
+    }
+    column_footer {
+        value "Total"
+        value "$V{total}"
+    }
+}
+"#;
+    // Rows are generated as they are read; none of them is kept by the caller.
+    let count = 10_000;
+    let mut rows = (0..count).map(|i| {
+        Ok(json!({
+            "name": format!("Person {}", i),
+            "department": i / 1000,
+            "team": i / 250,
+            "salary": i,
+        }))
+    });
+    let doc = Template::parse(template)?;
+    let text = String::from_utf8(
+        doc.render_source(&mut rows, None, &HashMap::new(), OutputFormat::Text)?
+            .to_vec(),
+    )?;
+    assert!(text.contains("10000 people"));
+    assert!(text.contains("Department 0: 1000 people"));
+    assert!(text.contains(&format!("{}", (0..1000).sum::<i64>())));
+    assert!(text.contains("Team 3"));
+    assert!(text.contains(&format!("{}", (0..count).sum::<i64>())));
+    let department = text.find("Department 1: 1000 people").unwrap();
+    assert!(text[..department].contains("Person 999"));
+    assert!(!text[..department].contains("Person 1000"));
+
+    let mut failing = vec![
+        Ok(json!({"name": "John"})),
+        Err(ReportError::Common("Broken".to_string())),
+    ]
+    .into_iter();
+    let error = Report::generate_from_source(
+        template,
+        &mut failing,
+        None,
+        &HashMap::new(),
+        OutputFormat::Text,
+    )
+    .unwrap_err();
+    assert_eq!(error.to_string(), "Common error: Broken");
+    Ok(())
+}
+
+#[test]
+fn test_data_layout() -> anyhow::Result<()> {
+    let template = minimal_template(
+        "$F(name) earns $F(salary)",
+        "$V{total} $V{order_total} $P{company_name}",
+    )
+    .replace(
+        "template {\n",
+        r#"template {
+    data rows="/result/items" params="$.meta"
+    dataset name="orders" rows="$.result.orders[*]"
+    variable name="order_total" calc="sum" field="amount" dataset="orders"
+"#,
+    );
+    let data = r#"{
+        "result": {
+            "items": [{"name": "John", "salary": 50000}, {"name": "Jane", "salary": 60000}],
+            "orders": [{"amount": 10}, {"amount": 5.5}]
+        },
+        "meta": {"company_name": "ABCDFG Ltd"}
+    }"#;
+    let text = String::from_utf8(
+        Report::generate(&template, data, &HashMap::new(), OutputFormat::Text)?.to_vec(),
+    )?;
+    assert!(text.contains("John earns 50000"));
+    assert!(text.contains("Jane earns 60000"));
+    assert!(text.contains("110000 15.5 ABCDFG Ltd"));
+    assert!(Report::validate_with_data(&template, data).is_empty());
+    let problems = Report::validate_with_data(&template.replace("$F(salary)", "$F(pay)"), data);
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].to_string().contains("Unknown field '$F(pay)'"));
+
+    // Paths selecting the same rows each get all of them.
+    let shared = template
+        .replace("$.result.orders[*]", "/result/items")
+        .replace("field=\"amount\"", "field=\"salary\"");
+    let text = String::from_utf8(
+        Report::generate(&shared, data, &HashMap::new(), OutputFormat::Text)?.to_vec(),
+    )?;
+    assert!(text.contains("Jane earns 60000"));
+    assert!(text.contains("110000 110000 ABCDFG Ltd"));
+
+    // A JSONPath selecting single rows rather than an array.
+    let second = template.replace("/result/items", "$.result.items[-1]");
+    let text = String::from_utf8(
+        Report::generate(&second, data, &HashMap::new(), OutputFormat::Text)?.to_vec(),
+    )?;
+    assert!(text.contains("Jane earns 60000"));
+    assert!(!text.contains("John"));
+
+    let missing = template.replace("/result/items", "/result/nothing");
+    let error = Report::generate(&missing, data, &HashMap::new(), OutputFormat::Text).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Invalid data: No rows at '/result/nothing'"
+    );
+    assert_eq!(error.code(), "data_error");
+    let error = Report::generate(
+        &template.replace("/result/items", "/meta"),
+        data,
+        &HashMap::new(),
+        OutputFormat::Text,
+    )
+    .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Invalid data: Rows at '/meta' must be an array"
+    );
+
+    let error = Template::parse(&template.replace("$.meta", "meta")).unwrap_err();
+    assert_eq!(error.code(), "invalid_template");
+    assert!(error.to_string().contains("Invalid 'params'"));
+    let error =
+        Template::parse(&template.replace("dataset=\"orders\"", "dataset=\"sales\"")).unwrap_err();
+    assert!(error.to_string().contains("Unknown dataset 'sales'"));
+
+    // Datasets are read from JSON data, so CSV rows leave them undefined.
+    let error = Report::generate_from_csv(
+        &template,
+        b"name,salary\nJohn,50000\n",
+        Some(r#"{"company_name": "ABCDFG Ltd"}"#),
+        &CsvOptions::default(),
+        &HashMap::new(),
+        OutputFormat::Text,
+    )
+    .unwrap_err();
+    assert_eq!(error.code(), "data_error");
+    assert_eq!(
+        error.to_string(),
+        "Invalid data: Dataset 'orders' of variable 'order_total' is only available in JSON data"
+    );
+
+    let path: DataPath = "$.a['b.c'][-1].*".parse().map_err(anyhow::Error::msg)?;
+    let value = json!({"a": {"b.c": [1, {"x": 2, "y": 3}]}});
+    assert_eq!(path.select(&value), vec![&json!(2), &json!(3)]);
+    let mut value = value;
+    let last: DataPath = "$.a['b.c'][-1]".parse().map_err(anyhow::Error::msg)?;
+    last.select_mut(&mut value).unwrap()["x"] = json!(4);
+    assert_eq!(value["a"]["b.c"][1]["x"], 4);
+    assert!(path.select_mut(&mut value).is_none());
+    assert!("$.a[x]".parse::<DataPath>().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_nested_fields() -> anyhow::Result<()> {
+    let template = minimal_template(
+        "$F(name) of $F(address.city) ordered $F(items[0].sku)",
+        "$V{total} for $P{company.name} in $P{company.offices[-1]}",
+    )
+    .replace("field=\"salary\"", "field=\"pay.amount\"");
+    let data = r#"{
+        "rows": [
+            {"name": "John", "address": {"city": "Oslo"}, "items": [{"sku": "A-1"}], "pay": {"amount": 100}},
+            {"name": "Jane", "address": {}, "items": [{"sku": "B-2"}], "pay": {"amount": 50}}
+        ],
+        "params": {"company": {"name": "ABCDFG Ltd", "offices": ["Oslo", "Bergen"]}}
+    }"#;
+    let text = String::from_utf8(
+        Report::generate(&template, data, &HashMap::new(), OutputFormat::Text)?.to_vec(),
+    )?;
+    assert!(text.contains("John of Oslo ordered A-1"));
+    assert!(text.contains("Jane of  ordered B-2"));
+    assert!(text.contains("150 for ABCDFG Ltd in Bergen"));
+    assert!(Report::validate_with_data(&template, data).is_empty());
+    let problems =
+        Report::validate_with_data(&template.replace("$F(address.city)", "$F(home.city)"), data);
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0]
+        .to_string()
+        .contains("Unknown field '$F(home.city)'"));
+
+    // A missing intermediate value fails, unless blanks are asked for.
+    let data = data.replace(r#""address": {}, "#, "");
+    let error =
+        Report::generate(&template, &data, &HashMap::new(), OutputFormat::Text).unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("Missing 'address' in 'address.city'"));
+    let blank = template.replace("template {", "template blank_missing=true {");
+    let text = String::from_utf8(
+        Report::generate(&blank, &data, &HashMap::new(), OutputFormat::Text)?.to_vec(),
+    )?;
+    assert!(text.contains("Jane of  ordered B-2"));
+
+    // A top-level key containing a dot is still read as a whole.
+    let dotted =
+        r#"{"rows": [{"name": "Ann", "address.city": "Rome", "items": []}], "params": {}}"#;
+    let text = String::from_utf8(
+        Report::generate(&blank, dotted, &HashMap::new(), OutputFormat::Text)?.to_vec(),
+    )?;
+    assert!(text.contains("Ann of Rome ordered"));
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use crate::registry::RegistryError;
+use axum::extract::multipart::{MultipartError, MultipartRejection};
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use metatron::ReportError;
+use serde::Serialize;
+
+/// Error sent to clients as `{"code": ..., "message": ..., "location": ...}`,
+/// `code` being a stable name of the kind of error.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    status: StatusCode,
+    body: Box<ErrorBody>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<Location>,
+    /// Each problem, when there are several, e.g. in an invalid template.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<ErrorBody>,
+}
+
+/// Where in the request the error was found.
+#[derive(Debug, Serialize)]
+#[serde(tag = "source", rename_all = "lowercase")]
+enum Location {
+    Template {
+        line: usize,
+        column: usize,
+        offset: usize,
+        length: usize,
+        path: Vec<String>,
+    },
+    Data {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        line: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        column: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        row: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        field: Option<String>,
+    },
+}
+
+impl ApiError {
+    pub(crate) fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            body: Box::new(ErrorBody {
+                code,
+                message: message.into(),
+                location: None,
+                errors: Vec::new(),
+            }),
+        }
+    }
+
+    /// Malformed request, e.g. a missing multipart part.
+    pub(crate) fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_request", message)
+    }
+
+    pub(crate) fn internal(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message)
+    }
+}
+
+fn status(error: &ReportError) -> StatusCode {
+    match error {
+        ReportError::InvalidDocumentType(_) | ReportError::InvalidOption(_) => {
+            StatusCode::BAD_REQUEST
+        }
+        ReportError::DataRow { source, .. } => status(source),
+        ReportError::Common(_) | ReportError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+fn location(error: &ReportError) -> Option<Location> {
+    if let Some(location) = error.location() {
+        return Some(Location::Template {
+            line: location.line,
+            column: location.column,
+            offset: location.offset,
+            length: location.len,
+            path: location.path,
+        });
+    }
+    match error {
+        ReportError::JsonParseError(e) => Some(Location::Data {
+            line: Some(e.line()),
+            column: Some(e.column()),
+            row: None,
+            field: None,
+        }),
+        ReportError::CsvParseError(e) => e.position().map(|position| Location::Data {
+            line: Some(position.line() as usize),
+            column: None,
+            row: None,
+            field: None,
+        }),
+        ReportError::MissingField { row, field } => Some(Location::Data {
+            line: None,
+            column: None,
+            row: Some(*row),
+            field: Some(field.clone()),
+        }),
+        ReportError::DataRow { row, .. } => Some(Location::Data {
+            line: None,
+            column: None,
+            row: Some(*row),
+            field: None,
+        }),
+        _ => None,
+    }
+}
+
+impl From<&ReportError> for ErrorBody {
+    fn from(error: &ReportError) -> Self {
+        ErrorBody {
+            code: error.code(),
+            message: error.to_string(),
+            location: location(error),
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl From<&ReportError> for ApiError {
+    fn from(error: &ReportError) -> Self {
+        ApiError {
+            status: status(error),
+            body: Box::new(error.into()),
+        }
+    }
+}
+
+impl From<ReportError> for ApiError {
+    fn from(error: ReportError) -> Self {
+        (&error).into()
+    }
+}
+
+impl From<RegistryError> for ApiError {
+    fn from(error: RegistryError) -> Self {
+        let (status, code) = match &error {
+            RegistryError::InvalidName(_) => (StatusCode::BAD_REQUEST, "invalid_name"),
+            RegistryError::NotFound(_) => (StatusCode::NOT_FOUND, "template_not_found"),
+            RegistryError::AlreadyExists(_) => (StatusCode::CONFLICT, "template_exists"),
+            RegistryError::Invalid(_) => (StatusCode::UNPROCESSABLE_ENTITY, "invalid_template"),
+            RegistryError::IoError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "io_error"),
+        };
+        let mut api_error = ApiError::new(status, code, error.to_string());
+        if let RegistryError::Invalid(problems) = &error {
+            api_error.body.errors = problems.iter().map(ErrorBody::from).collect();
+        }
+        api_error
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            JsonRejection::MissingJsonContentType(_) => ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                rejection.body_text(),
+            ),
+            JsonRejection::JsonDataError(_) => ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_request",
+                rejection.body_text(),
+            ),
+            _ => ApiError::new(rejection.status(), "invalid_request", rejection.body_text()),
+        }
+    }
+}
+
+impl From<MultipartRejection> for ApiError {
+    fn from(rejection: MultipartRejection) -> Self {
+        match rejection {
+            // A body that is not `multipart/form-data` has no boundary.
+            MultipartRejection::InvalidBoundary(_) => ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                rejection.body_text(),
+            ),
+            _ => ApiError::new(rejection.status(), "invalid_request", rejection.body_text()),
+        }
+    }
+}
+
+impl From<MultipartError> for ApiError {
+    fn from(error: MultipartError) -> Self {
+        ApiError::new(error.status(), "invalid_request", error.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::bad_request(rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body)).into_response()
+    }
+}
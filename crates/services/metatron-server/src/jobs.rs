@@ -0,0 +1,317 @@
+use crate::error::ApiError;
+use crate::{output_format, respond, CreateDocument};
+use axum::body::Bytes;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use metatron::{OutputFormat, Report, ReportError, ResourceResolver};
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Settings of a [`JobQueue`].
+#[derive(Debug, Clone)]
+pub struct JobsConfig {
+    /// Number of reports rendered at the same time, each on a blocking thread,
+    /// by jobs and by requests rendering directly.
+    pub workers: usize,
+    /// Number of queued and running jobs beyond which new jobs are refused.
+    pub queue_limit: usize,
+    /// How long results of finished jobs are kept.
+    pub ttl: Duration,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            workers: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            queue_limit: 100,
+            ttl: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+struct Job {
+    seq: u64,
+    status: JobStatus,
+    format: OutputFormat,
+    filename: Option<String>,
+    result: Option<Result<Bytes, ReportError>>,
+    finished: Option<Instant>,
+}
+
+/// Reports rendered in the background by a bounded pool of blocking threads.
+/// Jobs are kept in memory; finished ones are dropped once their TTL expires.
+pub struct JobQueue {
+    config: JobsConfig,
+    jobs: Mutex<HashMap<String, Job>>,
+    next_seq: AtomicU64,
+    workers: Arc<Semaphore>,
+    ids: RandomState,
+}
+
+impl JobQueue {
+    pub fn new(config: JobsConfig) -> Self {
+        let workers = Arc::new(Semaphore::new(config.workers.max(1)));
+        JobQueue {
+            config,
+            jobs: Mutex::new(HashMap::new()),
+            next_seq: AtomicU64::new(0),
+            workers,
+            ids: RandomState::new(),
+        }
+    }
+
+    /// Enqueues rendering `payload` and returns the id of the job, or `None`
+    /// if the queue is full.
+    fn submit(
+        self: &Arc<Self>,
+        resolver: Arc<dyn ResourceResolver>,
+        payload: CreateDocument,
+        format: OutputFormat,
+    ) -> Option<String> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        // Ids are unguessable, as anyone knowing one may download the report.
+        let id = format!(
+            "{:016x}{:016x}",
+            self.ids.hash_one((seq, 0u8)),
+            self.ids.hash_one((seq, 1u8))
+        );
+        let mut jobs = self.lock();
+        let pending = jobs.values().filter(|job| job.finished.is_none()).count();
+        if pending >= self.config.queue_limit {
+            return None;
+        }
+        jobs.insert(
+            id.clone(),
+            Job {
+                seq,
+                status: JobStatus::Queued,
+                format,
+                filename: payload.filename.clone(),
+                result: None,
+                finished: None,
+            },
+        );
+        drop(jobs);
+        tokio::spawn(self.clone().run(id.clone(), resolver, payload, format));
+        Some(id)
+    }
+
+    async fn run(
+        self: Arc<Self>,
+        id: String,
+        resolver: Arc<dyn ResourceResolver>,
+        payload: CreateDocument,
+        format: OutputFormat,
+    ) {
+        let Ok(permit) = self.workers.clone().acquire_owned().await else {
+            return;
+        };
+        match self.lock().get_mut(&id) {
+            Some(job) => job.status = JobStatus::Running,
+            None => return,
+        }
+        let report = render_blocking(permit, move || {
+            Report::generate_with_resolver(
+                &payload.report_template,
+                &payload.report_data,
+                resolver.as_ref(),
+                format,
+            )
+        })
+        .await;
+        if let Some(job) = self.lock().get_mut(&id) {
+            job.status = match report {
+                Ok(_) => JobStatus::Done,
+                Err(_) => JobStatus::Failed,
+            };
+            job.result = Some(report);
+            job.finished = Some(Instant::now());
+        }
+    }
+
+    /// Runs `render` on a blocking thread once one of the `workers` is free,
+    /// so that reports rendered within requests share the threads of jobs.
+    pub(crate) async fn render(
+        &self,
+        render: impl FnOnce() -> Result<Bytes, ReportError> + Send + 'static,
+    ) -> Result<Bytes, ReportError> {
+        let permit = self
+            .workers
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| ReportError::Common(format!("Rendering failed: {}", e)))?;
+        render_blocking(permit, render).await
+    }
+
+    /// Cancels job `id`, dropping its result. A job already rendering runs to
+    /// completion, but its result is discarded.
+    fn cancel(&self, id: &str) -> bool {
+        self.lock().remove(id).is_some()
+    }
+
+    /// Locks the jobs, dropping those that expired.
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Job>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let ttl = self.config.ttl;
+        jobs.retain(|_, job| job.finished.is_none_or(|finished| finished.elapsed() < ttl));
+        jobs
+    }
+
+    fn info(&self, id: &str, jobs: &HashMap<String, Job>) -> Option<JobInfo> {
+        let job = jobs.get(id)?;
+        let position = (job.status == JobStatus::Queued).then(|| {
+            jobs.values()
+                .filter(|other| other.status == JobStatus::Queued && other.seq < job.seq)
+                .count()
+        });
+        Some(JobInfo {
+            id: id.to_string(),
+            status: job.status,
+            output_format: job.format.name(),
+            position,
+            error: match &job.result {
+                Some(Err(e)) => Some(e.to_string()),
+                _ => None,
+            },
+            expires_in: job
+                .finished
+                .map(|finished| self.config.ttl.saturating_sub(finished.elapsed()).as_secs()),
+        })
+    }
+}
+
+/// Runs `render` on a blocking thread. The permit is held until rendering
+/// ends, even if the job is cancelled or the request dropped meanwhile, so at
+/// most `workers` threads are ever busy.
+async fn render_blocking(
+    permit: OwnedSemaphorePermit,
+    render: impl FnOnce() -> Result<Bytes, ReportError> + Send + 'static,
+) -> Result<Bytes, ReportError> {
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        render()
+    })
+    .await
+    .unwrap_or_else(|e| Err(ReportError::Common(format!("Rendering failed: {}", e))))
+}
+
+#[derive(Clone)]
+struct JobsState {
+    resolver: Arc<dyn ResourceResolver>,
+    jobs: Arc<JobQueue>,
+}
+
+/// Routes of the `/jobs` API.
+pub(crate) fn router(resolver: Arc<dyn ResourceResolver>, jobs: Arc<JobQueue>) -> Router {
+    Router::new()
+        .route("/jobs", post(submit))
+        .route("/jobs/:id", get(status).delete(cancel))
+        .route("/jobs/:id/result", get(result))
+        .with_state(JobsState { resolver, jobs })
+}
+
+#[derive(Serialize)]
+struct JobInfo {
+    id: String,
+    status: JobStatus,
+    output_format: &'static str,
+    /// Number of queued jobs ahead of this one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Seconds until a finished job is dropped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in: Option<u64>,
+}
+
+async fn submit(
+    State(state): State<JobsState>,
+    payload: Result<Json<CreateDocument>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(payload) = payload?;
+    let format = output_format(&payload.output_format)?;
+    let Some(id) = state.jobs.submit(state.resolver.clone(), payload, format) else {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "queue_full",
+            "Too many pending jobs",
+        ));
+    };
+    let info = state.jobs.info(&id, &state.jobs.lock());
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, format!("/jobs/{}", id))],
+        Json(info),
+    )
+        .into_response())
+}
+
+async fn status(
+    State(state): State<JobsState>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    match state.jobs.info(&id, &state.jobs.lock()) {
+        Some(info) => Ok(Json(info).into_response()),
+        None => Err(not_found(&id)),
+    }
+}
+
+async fn result(
+    State(state): State<JobsState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let jobs = state.jobs.lock();
+    let job = jobs.get(&id).ok_or_else(|| not_found(&id))?;
+    let (format, filename) = (job.format, job.filename.clone());
+    let report = match &job.result {
+        Some(Ok(report)) => report.clone(),
+        Some(Err(e)) => return Err(e.into()),
+        None => {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "job_not_finished",
+                format!("Job {} has not finished yet", id),
+            ))
+        }
+    };
+    drop(jobs);
+    respond(Ok(report), format, filename.as_deref(), &headers)
+}
+
+async fn cancel(State(state): State<JobsState>, Path(id): Path<String>) -> Response {
+    if state.jobs.cancel(&id) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        not_found(&id).into_response()
+    }
+}
+
+fn not_found(id: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "job_not_found",
+        format!("Job not found: {}", id),
+    )
+}
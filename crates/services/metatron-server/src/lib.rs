@@ -1,54 +1,301 @@
+use axum::extract::multipart::MultipartRejection;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{DefaultBodyLimit, Multipart, State};
+use axum::http::{header, HeaderMap};
 use axum::{routing::post, Router};
-use mime::APPLICATION_PDF;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
 
-use axum::body::Body;
-use axum::response::{IntoResponse, Response};
+use axum::body::{Body, Bytes};
+use axum::response::Response;
 use axum::{http::StatusCode, Json};
-use metatron::Report;
+use metatron::{
+    DenyAllResolver, MemoryResolver, OutputFormat, Report, ReportError, ResourceResolver,
+};
 use serde::Deserialize;
 
-async fn handler(Json(payload): Json<CreateDocument>) -> impl IntoResponse {
-    let images = HashMap::new();
-    let report = Report::generate(
-        &payload.report_template,
-        &payload.report_data,
-        &images,
-        &payload.output_format,
-    );
-
-    let Ok(report) = report else {
-        return Err((
-            StatusCode::NOT_ACCEPTABLE,
-            format!("File is corrupted: {}", report.err().unwrap()),
-        ));
+mod error;
+mod jobs;
+mod registry;
+mod templates;
+
+use error::ApiError;
+pub use jobs::{JobQueue, JobsConfig};
+pub use registry::{RegistryError, TemplateRegistry};
+
+/// Maximum size of a multipart upload, images included.
+const UPLOAD_LIMIT: usize = 32 * 1024 * 1024;
+
+/// What the `/generate` routes render with.
+#[derive(Clone)]
+struct GenerateState {
+    resolver: Arc<dyn ResourceResolver>,
+    /// Reports are rendered on the blocking threads of the job queue.
+    jobs: Arc<JobQueue>,
+}
+
+async fn handler(
+    State(state): State<GenerateState>,
+    headers: HeaderMap,
+    payload: Result<Json<CreateDocument>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(payload) = payload?;
+    generate(&state.jobs, state.resolver, &headers, payload).await
+}
+
+/// Same as [`handler`] for a `multipart/form-data` upload of `template` and
+/// `data` files, `output_format` and `filename` fields and any number of
+/// `image` files, which the template refers to by file name.
+async fn upload_handler(
+    State(state): State<GenerateState>,
+    headers: HeaderMap,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, ApiError> {
+    let (payload, images) = read_upload(multipart?).await?;
+    let resolver = Arc::new(UploadedImages {
+        images: MemoryResolver::new(images),
+        fallback: state.resolver,
+    });
+    generate(&state.jobs, resolver, &headers, payload).await
+}
+
+async fn read_upload(
+    mut multipart: Multipart,
+) -> Result<(CreateDocument, HashMap<String, Bytes>), ApiError> {
+    let mut template = None;
+    let mut data = None;
+    let mut output_format = None;
+    let mut filename = None;
+    let mut images = HashMap::new();
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "image" {
+            let file_name = field
+                .file_name()
+                .ok_or_else(|| ApiError::bad_request("Missing file name of 'image' part"))?
+                .to_string();
+            let bytes = field.bytes().await?;
+            images.insert(file_name, bytes);
+            continue;
+        }
+        let text = field.text().await?;
+        match name.as_str() {
+            "template" => template = Some(text),
+            "data" => data = Some(text),
+            "output_format" => output_format = Some(text),
+            "filename" => filename = Some(text),
+            _ => return Err(ApiError::bad_request(format!("Unknown part '{}'", name))),
+        }
+    }
+    let payload = CreateDocument {
+        report_template: template.ok_or_else(|| missing_part("template"))?,
+        report_data: data.ok_or_else(|| missing_part("data"))?,
+        output_format: output_format.ok_or_else(|| missing_part("output_format"))?,
+        filename,
     };
+    Ok((payload, images))
+}
+
+fn missing_part(name: &str) -> ApiError {
+    ApiError::bad_request(format!("Missing '{}' part", name))
+}
+
+/// Resolves uploaded images first, then whatever the server allows.
+struct UploadedImages {
+    images: MemoryResolver,
+    fallback: Arc<dyn ResourceResolver>,
+}
 
-    let body = Body::from(report);
-
-    let response = Response::builder()
-        .header("Content-Encoding", "gzip")
-        .header("Content-Type", APPLICATION_PDF.as_ref())
-        .header("Cache-Control", "public, max-age=31536000")
-        .status(StatusCode::OK)
-        .body(body);
-
-    match response {
-        Ok(response) => Ok(response),
-        Err(e) => Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to create response: {}", e),
-        )),
+impl ResourceResolver for UploadedImages {
+    fn resolve(&self, name: &str) -> Result<Option<Bytes>, ReportError> {
+        match self.images.resolve(name)? {
+            Some(bytes) => Ok(Some(bytes)),
+            None => self.fallback.resolve(name),
+        }
     }
 }
 
+async fn generate(
+    jobs: &JobQueue,
+    resolver: Arc<dyn ResourceResolver>,
+    headers: &HeaderMap,
+    payload: CreateDocument,
+) -> Result<Response, ApiError> {
+    let format = output_format(&payload.output_format)?;
+    let filename = payload.filename.clone();
+
+    let report = jobs
+        .render(move || {
+            Report::generate_with_resolver(
+                &payload.report_template,
+                &payload.report_data,
+                resolver.as_ref(),
+                format,
+            )
+        })
+        .await;
+    respond(report, format, filename.as_deref(), headers)
+}
+
+/// Builds the response for a generated `report`, with headers following
+/// `format` and the body compressed if the request `headers` allow it.
+pub(crate) fn respond(
+    report: Result<Bytes, ReportError>,
+    format: OutputFormat,
+    filename: Option<&str>,
+    headers: &HeaderMap,
+) -> Result<Response, ApiError> {
+    let report = report?;
+
+    let content_type = if format.is_text() {
+        format!("{}; charset=utf-8", format.mime_type())
+    } else {
+        format.mime_type().to_string()
+    };
+
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CACHE_CONTROL, "public, max-age=31536000")
+        .header(header::VARY, "Accept-Encoding");
+
+    if let Some(filename) = filename {
+        response = response.header(
+            header::CONTENT_DISPOSITION,
+            content_disposition(filename, format),
+        );
+    }
+
+    let body = if accepts_gzip(headers) {
+        response = response.header(header::CONTENT_ENCODING, "gzip");
+        match gzip(&report) {
+            Ok(bytes) => Body::from(bytes),
+            Err(e) => {
+                return Err(ApiError::internal(format!(
+                    "Failed to compress response: {}",
+                    e
+                )))
+            }
+        }
+    } else {
+        Body::from(report)
+    };
+
+    let response = response.status(StatusCode::OK).body(body);
+
+    response.map_err(|e| ApiError::internal(format!("Failed to create response: {}", e)))
+}
+
+/// Whether the client accepts a gzip encoded body, per `Accept-Encoding`.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    let mut gzip = None;
+    let mut any = None;
+    for value in headers.get_all(header::ACCEPT_ENCODING) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for coding in value.split(',') {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match name.as_str() {
+                "gzip" | "x-gzip" => gzip = Some(quality > 0.0),
+                "*" => any = Some(quality > 0.0),
+                _ => {}
+            }
+        }
+    }
+    gzip.or(any).unwrap_or(false)
+}
+
+fn gzip(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+/// `Content-Disposition` for downloading the report as `filename`, with the
+/// extension of `format` added if it has none.
+fn content_disposition(filename: &str, format: OutputFormat) -> String {
+    let mut filename: String = filename
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '"' | '\\' | '/'))
+        .collect();
+    if filename.is_empty() {
+        filename = "report".to_string();
+    }
+    if !filename.contains('.') {
+        filename = format!("{}.{}", filename, format.extension());
+    }
+    format!("attachment; filename=\"{}\"", filename)
+}
+
+/// Parses the requested output format.
+pub(crate) fn output_format(name: &str) -> Result<OutputFormat, ApiError> {
+    name.parse().map_err(|e: ReportError| e.into())
+}
+
+/// Router whose templates cannot read any external resources.
 pub fn router() -> Router {
-    Router::new().route("/generate", post(handler))
+    router_with_resolver(Arc::new(DenyAllResolver))
+}
+
+/// Router resolving template resources such as `image src` with `resolver`.
+pub fn router_with_resolver(resolver: Arc<dyn ResourceResolver>) -> Router {
+    router_with_options(
+        resolver,
+        None,
+        Arc::new(JobQueue::new(JobsConfig::default())),
+    )
+}
+
+/// Like [`router_with_resolver`], also serving the `/templates` API backed by `templates`.
+pub fn router_with_templates(
+    resolver: Arc<dyn ResourceResolver>,
+    templates: Arc<TemplateRegistry>,
+) -> Router {
+    router_with_options(
+        resolver,
+        Some(templates),
+        Arc::new(JobQueue::new(JobsConfig::default())),
+    )
+}
+
+/// Router with the `/jobs` API run by `jobs`, and the `/templates` API if
+/// `templates` is given.
+pub fn router_with_options(
+    resolver: Arc<dyn ResourceResolver>,
+    templates: Option<Arc<TemplateRegistry>>,
+    jobs: Arc<JobQueue>,
+) -> Router {
+    let router = Router::new()
+        .route("/generate", post(handler))
+        .route(
+            "/generate/multipart",
+            post(upload_handler).layer(DefaultBodyLimit::max(UPLOAD_LIMIT)),
+        )
+        .with_state(GenerateState {
+            resolver: resolver.clone(),
+            jobs: jobs.clone(),
+        })
+        .merge(jobs::router(resolver.clone(), jobs.clone()));
+    match templates {
+        Some(templates) => router.merge(templates::router(resolver, templates, jobs)),
+        None => router,
+    }
 }
 
 #[derive(Deserialize)]
-struct CreateDocument {
+pub(crate) struct CreateDocument {
     pub report_template: String,
     pub report_data: String,
     pub output_format: String,
+    /// Name to download the report as, sent in `Content-Disposition`.
+    #[serde(default)]
+    pub filename: Option<String>,
 }
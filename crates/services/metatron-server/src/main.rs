@@ -1,5 +1,9 @@
-use metatron_server::router;
+use metatron::{DenyAllResolver, DirectoryResolver, ResourceResolver};
+use metatron_server::{router_with_options, JobQueue, JobsConfig, TemplateRegistry};
 use clap::Parser;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 
 #[derive(Parser, Debug)]
@@ -18,6 +22,34 @@ struct Args {
         help = "The port number to bind the server to",
     )]
     port: u16,
+
+    #[arg(
+        short,
+        long,
+        help = "Directory templates may read images from; no resources are readable if omitted",
+    )]
+    resources: Option<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        help = "Directory to store templates of the /templates API in; the API is disabled if omitted",
+    )]
+    templates: Option<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        help = "Number of reports rendered at the same time, by requests and jobs alike [default: number of CPUs]",
+    )]
+    workers: Option<usize>,
+
+    #[arg(
+        long,
+        default_value_t = 900,
+        help = "Seconds the /jobs API keeps results of finished jobs",
+    )]
+    job_ttl: u64,
 }
 
 #[tokio::main]
@@ -29,5 +61,18 @@ async fn main() {
     let address = format!("0.0.0.0:{}", args.port);
     println!("Listening on {}", address);
     let listener = tokio::net::TcpListener::bind(&address).await.unwrap();
-    axum::serve(listener, router()).await.unwrap();
+    let resolver: Arc<dyn ResourceResolver> = match args.resources {
+        Some(root) => Arc::new(DirectoryResolver::new(root)),
+        None => Arc::new(DenyAllResolver),
+    };
+    let templates = args.templates.map(|root| {
+        Arc::new(TemplateRegistry::new(root).expect("Failed to open template directory"))
+    });
+    let mut jobs = JobsConfig::default();
+    if let Some(workers) = args.workers {
+        jobs.workers = workers;
+    }
+    jobs.ttl = Duration::from_secs(args.job_ttl);
+    let router = router_with_options(resolver, templates, Arc::new(JobQueue::new(jobs)));
+    axum::serve(listener, router).await.unwrap();
 }
@@ -0,0 +1,222 @@
+use metatron::{CompiledTemplate, Report, ReportError, Template};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+/// File in a template's directory holding the highest version it ever had.
+const LATEST_VERSION: &str = "latest_version";
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("Invalid template name: {0}")]
+    InvalidName(String),
+
+    #[error("Template not found: {0}")]
+    NotFound(String),
+
+    #[error("Template already exists: {0}")]
+    AlreadyExists(String),
+
+    #[error("Invalid template: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+    Invalid(Vec<ReportError>),
+
+    #[error("I/O error: {0}")]
+    IoError(#[from] io::Error),
+}
+
+/// Named, versioned templates stored as `<root>/<name>/<version>.kdl`.
+/// Versions are never modified once written, and their numbers are never
+/// reused: `<root>/<name>/latest_version` keeps the highest one written, also
+/// after the template is deleted. Compiled templates are therefore cached by
+/// name and version.
+pub struct TemplateRegistry {
+    root: PathBuf,
+    cache: Mutex<HashMap<(String, u32), Arc<CompiledTemplate>>>,
+    /// Serializes changes, so that concurrent uploads get distinct versions.
+    write_lock: Mutex<()>,
+}
+
+impl TemplateRegistry {
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(TemplateRegistry {
+            root,
+            cache: Mutex::new(HashMap::new()),
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// Names of all stored templates, sorted.
+    pub fn names(&self) -> Result<Vec<String>, RegistryError> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                // A deleted template leaves a directory without versions.
+                if entry.file_type()?.is_dir() && valid_name(name) && self.versions(name).is_ok() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Versions of template `name`, oldest first.
+    pub fn versions(&self, name: &str) -> Result<Vec<u32>, RegistryError> {
+        let dir = self.dir(name)?;
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(RegistryError::NotFound(name.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut versions = Vec::new();
+        for entry in entries {
+            let file_name = entry?.file_name();
+            let version = file_name
+                .to_str()
+                .and_then(|file_name| file_name.strip_suffix(".kdl"))
+                .and_then(|version| version.parse::<u32>().ok());
+            versions.extend(version);
+        }
+        if versions.is_empty() {
+            return Err(RegistryError::NotFound(name.to_string()));
+        }
+        versions.sort();
+        Ok(versions)
+    }
+
+    /// Stores a new template `name` as version 1, or as the version after
+    /// those of a deleted template of the same name.
+    pub fn create(&self, name: &str, template: &str) -> Result<u32, RegistryError> {
+        let _guard = self.write_lock.lock().unwrap();
+        if self.versions(name).is_ok() {
+            return Err(RegistryError::AlreadyExists(name.to_string()));
+        }
+        let version = self.next_version(name)?;
+        self.write(name, version, template)
+    }
+
+    /// Stores `template` as the next version of `name`, creating it if needed.
+    pub fn update(&self, name: &str, template: &str) -> Result<u32, RegistryError> {
+        let _guard = self.write_lock.lock().unwrap();
+        let version = self.next_version(name)?;
+        self.write(name, version, template)
+    }
+
+    /// Returns the source of `version` of `name`, or of its latest version.
+    pub fn get(&self, name: &str, version: Option<u32>) -> Result<(u32, String), RegistryError> {
+        let version = self.resolve_version(name, version)?;
+        let template = fs::read_to_string(self.dir(name)?.join(format!("{}.kdl", version)))?;
+        Ok((version, template))
+    }
+
+    /// Returns `version` of `name`, or its latest version, compiled.
+    pub fn compiled(
+        &self,
+        name: &str,
+        version: Option<u32>,
+    ) -> Result<(u32, Arc<CompiledTemplate>), RegistryError> {
+        let version = self.resolve_version(name, version)?;
+        let key = (name.to_string(), version);
+        if let Some(compiled) = self.cache.lock().unwrap().get(&key) {
+            return Ok((version, compiled.clone()));
+        }
+        let (_, template) = self.get(name, Some(version))?;
+        let compiled =
+            Arc::new(Template::parse(&template).map_err(|e| RegistryError::Invalid(vec![e]))?);
+        self.cache.lock().unwrap().insert(key, compiled.clone());
+        Ok((version, compiled))
+    }
+
+    /// Deletes `version` of `name`, or all of its versions.
+    pub fn delete(&self, name: &str, version: Option<u32>) -> Result<(), RegistryError> {
+        let _guard = self.write_lock.lock().unwrap();
+        let versions = self.versions(name)?;
+        let dir = self.dir(name)?;
+        match version {
+            Some(version) if !versions.contains(&version) => {
+                return Err(RegistryError::NotFound(format!(
+                    "{} version {}",
+                    name, version
+                )))
+            }
+            Some(version) => fs::remove_file(dir.join(format!("{}.kdl", version)))?,
+            None => {
+                for version in versions {
+                    fs::remove_file(dir.join(format!("{}.kdl", version)))?;
+                }
+            }
+        }
+        self.cache
+            .lock()
+            .unwrap()
+            .retain(|(cached, cached_version), _| {
+                cached != name || version.is_some_and(|version| version != *cached_version)
+            });
+        Ok(())
+    }
+
+    fn resolve_version(&self, name: &str, version: Option<u32>) -> Result<u32, RegistryError> {
+        let versions = self.versions(name)?;
+        match version {
+            Some(version) if versions.contains(&version) => Ok(version),
+            Some(version) => Err(RegistryError::NotFound(format!(
+                "{} version {}",
+                name, version
+            ))),
+            None => Ok(*versions.last().unwrap()),
+        }
+    }
+
+    /// The version after the highest one `name` ever had.
+    fn next_version(&self, name: &str) -> Result<u32, RegistryError> {
+        let latest = match fs::read_to_string(self.dir(name)?.join(LATEST_VERSION)) {
+            Ok(latest) => latest.trim().parse().unwrap_or_default(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        // Templates stored before `latest_version` was kept only have their files.
+        let stored = match self.versions(name) {
+            Ok(versions) => versions.last().copied().unwrap_or_default(),
+            Err(RegistryError::NotFound(_)) => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(latest.max(stored) + 1)
+    }
+
+    fn write(&self, name: &str, version: u32, template: &str) -> Result<u32, RegistryError> {
+        let problems = Report::validate(template);
+        if !problems.is_empty() {
+            return Err(RegistryError::Invalid(problems));
+        }
+        let dir = self.dir(name)?;
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(format!("{}.kdl", version)), template)?;
+        fs::write(dir.join(LATEST_VERSION), version.to_string())?;
+        Ok(version)
+    }
+
+    fn dir(&self, name: &str) -> Result<PathBuf, RegistryError> {
+        if !valid_name(name) {
+            return Err(RegistryError::InvalidName(name.to_string()));
+        }
+        Ok(self.root.join(name))
+    }
+}
+
+/// Template names are non-empty and made of ASCII letters, digits, `-` and `_`,
+/// so they are safe to use as directory names.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 128
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
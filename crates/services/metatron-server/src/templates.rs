@@ -0,0 +1,146 @@
+use crate::error::ApiError;
+use crate::jobs::JobQueue;
+use crate::registry::TemplateRegistry;
+use crate::{output_format, respond};
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use metatron::ResourceResolver;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Clone)]
+struct TemplatesState {
+    resolver: Arc<dyn ResourceResolver>,
+    templates: Arc<TemplateRegistry>,
+    jobs: Arc<JobQueue>,
+}
+
+/// Routes of the `/templates` API.
+pub(crate) fn router(
+    resolver: Arc<dyn ResourceResolver>,
+    templates: Arc<TemplateRegistry>,
+    jobs: Arc<JobQueue>,
+) -> Router {
+    Router::new()
+        .route("/templates", get(list))
+        .route(
+            "/templates/:name",
+            post(create).get(read).put(update).delete(delete),
+        )
+        .route("/templates/:name/render", post(render))
+        .with_state(TemplatesState {
+            resolver,
+            templates,
+            jobs,
+        })
+}
+
+#[derive(Serialize)]
+struct TemplateInfo {
+    name: String,
+    versions: Vec<u32>,
+}
+
+#[derive(Serialize)]
+struct TemplateVersion {
+    name: String,
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct VersionQuery {
+    version: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct RenderTemplate {
+    report_data: String,
+    output_format: String,
+    /// Version to render; the latest if absent.
+    #[serde(default)]
+    version: Option<u32>,
+    #[serde(default)]
+    filename: Option<String>,
+}
+
+async fn list(State(state): State<TemplatesState>) -> Result<Response, ApiError> {
+    let mut infos = Vec::new();
+    for name in state.templates.names()? {
+        let versions = state.templates.versions(&name)?;
+        infos.push(TemplateInfo { name, versions });
+    }
+    Ok(Json(infos).into_response())
+}
+
+async fn create(
+    State(state): State<TemplatesState>,
+    Path(name): Path<String>,
+    template: String,
+) -> Result<Response, ApiError> {
+    let version = state.templates.create(&name, &template)?;
+    Ok((StatusCode::CREATED, Json(TemplateVersion { name, version })).into_response())
+}
+
+async fn update(
+    State(state): State<TemplatesState>,
+    Path(name): Path<String>,
+    template: String,
+) -> Result<Response, ApiError> {
+    let version = state.templates.update(&name, &template)?;
+    Ok(Json(TemplateVersion { name, version }).into_response())
+}
+
+async fn read(
+    State(state): State<TemplatesState>,
+    Path(name): Path<String>,
+    query: Result<Query<VersionQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Query(query) = query?;
+    let (version, template) = state.templates.get(&name, query.version)?;
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/plain; charset=utf-8".to_string(),
+            ),
+            (
+                header::HeaderName::from_static("x-template-version"),
+                version.to_string(),
+            ),
+        ],
+        template,
+    )
+        .into_response())
+}
+
+async fn delete(
+    State(state): State<TemplatesState>,
+    Path(name): Path<String>,
+    query: Result<Query<VersionQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Query(query) = query?;
+    state.templates.delete(&name, query.version)?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn render(
+    State(state): State<TemplatesState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    payload: Result<Json<RenderTemplate>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(payload) = payload?;
+    let format = output_format(&payload.output_format)?;
+    let (_, compiled) = state.templates.compiled(&name, payload.version)?;
+    let resolver = state.resolver.clone();
+    let data = payload.report_data;
+    let report = state
+        .jobs
+        .render(move || compiled.render_with_resolver(&data, resolver.as_ref(), format))
+        .await;
+    respond(report, format, payload.filename.as_deref(), &headers)
+}
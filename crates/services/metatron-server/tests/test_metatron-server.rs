@@ -1,11 +1,20 @@
+use axum::body::Bytes;
+use axum_test::multipart::{MultipartForm, Part};
 use axum_test::TestServer;
+use flate2::read::GzDecoder;
 use http::header;
 use http::HeaderValue;
 use http::StatusCode;
-use metatron_server::router;
+use metatron::{DenyAllResolver, DirectoryResolver, ReportError, ResourceResolver};
+use metatron_server::{
+    router, router_with_options, router_with_resolver, router_with_templates, JobQueue, JobsConfig,
+    TemplateRegistry,
+};
 use mime::APPLICATION_PDF;
-use serde_json::json;
+use serde_json::{json, Value};
+use std::io::Read;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 #[tokio::test]
 async fn test_handler() {
@@ -16,10 +25,14 @@ async fn test_handler() {
         curdir.clone().into_string().unwrap()
     );
     let report_data = format!("{}/data/report-data.json", curdir.into_string().unwrap());
-    let srv = TestServer::new(router());
+    let srv = TestServer::new(router_with_resolver(Arc::new(DirectoryResolver::new(
+        "../../../",
+    ))));
 
     let payload = json!({
-        "report_template": std::fs::read_to_string(report_template).expect("Failed to read file"),
+        "report_template": std::fs::read_to_string(report_template)
+            .expect("Failed to read file")
+            .replace("../../../logo.png", "logo.png"),
         "report_data": std::fs::read_to_string(report_data).expect("Failed to read file"),
         "output_format": "pdf"
     });
@@ -31,6 +44,10 @@ async fn test_handler() {
             header::CONTENT_TYPE,
             HeaderValue::from_static(APPLICATION_PDF.as_ref()),
         )
+        .add_header(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("br;q=1.0, gzip;q=0.8"),
+        )
         .json(&payload)
         .await;
     assert_eq!(res.status_code(), StatusCode::OK);
@@ -43,5 +60,416 @@ async fn test_handler() {
         res.headers().get(header::CACHE_CONTROL).unwrap(),
         "public, max-age=31536000"
     );
+    let mut report = Vec::new();
+    GzDecoder::new(res.as_bytes().as_ref())
+        .read_to_end(&mut report)
+        .expect("Failed to decompress");
+    assert_eq!(report.len(), 499343);
+}
+
+#[tokio::test]
+async fn test_handler_headers_follow_format() {
+    let srv = TestServer::new(router()).unwrap();
+    let template = std::fs::read_to_string("../../../data/report-template.kdl")
+        .expect("Failed to read file")
+        .replace("image src=\"../../../logo.png\" width=100 height=100", "");
+
+    let payload = json!({
+        "report_template": template,
+        "report_data": std::fs::read_to_string("../../../data/report-data.json").expect("Failed to read file"),
+        "output_format": "html",
+        "filename": "staff \"2024\""
+    });
+
+    let res = srv
+        .post("/generate")
+        .add_header(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip;q=0, *"),
+        )
+        .json(&payload)
+        .await;
+    assert_eq!(res.status_code(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/html; charset=utf-8"
+    );
+    assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(
+        res.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"staff 2024.html\""
+    );
+    assert!(res.text().contains("ABCDFG Ltd Employee Report"));
+}
+
+#[tokio::test]
+async fn test_handler_denies_resources_by_default() {
+    let template = std::fs::read_to_string("../../../data/report-template.kdl")
+        .expect("Failed to read file")
+        .replace("../../../logo.png", "/etc/passwd");
+    let srv = TestServer::new(router()).unwrap();
+
+    let payload = json!({
+        "report_template": template,
+        "report_data": std::fs::read_to_string("../../../data/report-data.json").expect("Failed to read file"),
+        "output_format": "pdf"
+    });
+
+    let res = srv.post("/generate").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    let error = res.json::<Value>();
+    assert_eq!(error["code"], "resource_access_denied");
+    assert!(error["message"]
+        .as_str()
+        .unwrap()
+        .contains("Access to resource denied"));
+}
+
+#[tokio::test]
+async fn test_handler_rejects_unknown_format() {
+    let srv = TestServer::new(router()).unwrap();
+
+    let payload = json!({
+        "report_template": std::fs::read_to_string("../../../data/report-template.kdl").expect("Failed to read file"),
+        "report_data": std::fs::read_to_string("../../../data/report-data.json").expect("Failed to read file"),
+        "output_format": "xls"
+    });
+
+    let res = srv.post("/generate").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        res.json::<Value>(),
+        json!({"code": "invalid_format", "message": "Invalid document type: xls"})
+    );
+}
+
+#[tokio::test]
+async fn test_handler_error_responses() {
+    let srv = TestServer::new(router()).unwrap();
+    let template = std::fs::read_to_string("../../../data/report-template.kdl")
+        .expect("Failed to read file")
+        .replace("header level=1", "header level=9");
+    let data =
+        std::fs::read_to_string("../../../data/report-data.json").expect("Failed to read file");
+
+    let payload =
+        json!({"report_template": template, "report_data": data, "output_format": "html"});
+    let res = srv.post("/generate").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    let error = res.json::<Value>();
+    assert_eq!(error["code"], "invalid_template");
+    assert_eq!(error["location"]["source"], "template");
+    assert_eq!(
+        error["location"]["path"],
+        json!(["template", "title", "header"])
+    );
+
+    let payload =
+        json!({"report_template": "template {", "report_data": data, "output_format": "html"});
+    let res = srv.post("/generate").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(res.json::<Value>()["code"], "template_parse_error");
+
+    let template = std::fs::read_to_string("../../../data/report-template.kdl")
+        .expect("Failed to read file")
+        .replace("image src=\"../../../logo.png\" width=100 height=100", "");
+    let payload = json!({"report_template": template, "report_data": "{\n  \"rows\": [,]\n}", "output_format": "html"});
+    let res = srv.post("/generate").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    let error = res.json::<Value>();
+    assert_eq!(error["code"], "data_parse_error");
+    assert_eq!(
+        error["location"],
+        json!({"source": "data", "line": 2, "column": 12})
+    );
+
+    // Well-formed data of the wrong shape is the client's fault, not the server's.
+    let payload = json!({"report_template": template, "report_data": "{\"rows\": {}}", "output_format": "html"});
+    let res = srv.post("/generate").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(res.json::<Value>()["code"], "data_error");
+
+    let res = srv.post("/generate").text(payload.to_string()).await;
+    assert_eq!(res.status_code(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(res.json::<Value>()["code"], "unsupported_media_type");
+    let res = srv.post("/generate/multipart").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let res = srv
+        .post("/generate")
+        .json(&json!({"report_data": "{}"}))
+        .await;
+    assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(res.json::<Value>()["code"], "invalid_request");
+}
+
+#[tokio::test]
+async fn test_upload_handler() {
+    let srv = TestServer::new(router()).unwrap();
+    let template = std::fs::read_to_string("../../../data/report-template.kdl")
+        .expect("Failed to read file")
+        .replace("../../../logo.png", "logo.png");
+    let form = MultipartForm::new()
+        .add_part(
+            "template",
+            Part::text(template).file_name("report-template.kdl"),
+        )
+        .add_part(
+            "data",
+            Part::bytes(
+                std::fs::read("../../../data/report-data.json").expect("Failed to read file"),
+            )
+            .file_name("report-data.json")
+            .mime_type("application/json"),
+        )
+        .add_part(
+            "image",
+            Part::bytes(std::fs::read("../../../logo.png").expect("Failed to read file"))
+                .file_name("logo.png")
+                .mime_type("image/png"),
+        )
+        .add_text("output_format", "pdf");
+
+    let res = srv.post("/generate/multipart").multipart(form).await;
+    assert_eq!(res.status_code(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/pdf"
+    );
     assert_eq!(res.as_bytes().len(), 499343);
 }
+
+#[tokio::test]
+async fn test_upload_handler_requires_template() {
+    let srv = TestServer::new(router()).unwrap();
+    let form = MultipartForm::new()
+        .add_text("data", "{}")
+        .add_text("output_format", "pdf");
+
+    let res = srv.post("/generate/multipart").multipart(form).await;
+    assert_eq!(res.status_code(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        res.json::<Value>(),
+        json!({"code": "invalid_request", "message": "Missing 'template' part"})
+    );
+}
+
+#[tokio::test]
+async fn test_template_registry() {
+    let root = std::env::temp_dir().join(format!("metatron-templates-{}", std::process::id()));
+    let templates = Arc::new(TemplateRegistry::new(&root).unwrap());
+    let srv = TestServer::new(router_with_templates(Arc::new(DenyAllResolver), templates)).unwrap();
+    let template = std::fs::read_to_string("../../../data/report-template.kdl")
+        .expect("Failed to read file")
+        .replace("image src=\"../../../logo.png\" width=100 height=100", "");
+
+    let res = srv.post("/templates/staff").text(template.clone()).await;
+    assert_eq!(res.status_code(), StatusCode::CREATED);
+    assert_eq!(res.json::<Value>(), json!({"name": "staff", "version": 1}));
+    let res = srv.post("/templates/staff").text(template.clone()).await;
+    assert_eq!(res.status_code(), StatusCode::CONFLICT);
+
+    let res = srv
+        .put("/templates/staff")
+        .text(template.replace("Employee Report", "Staff List"))
+        .await;
+    assert_eq!(res.json::<Value>(), json!({"name": "staff", "version": 2}));
+    let res = srv
+        .put("/templates/staff")
+        .text("template {\n    banner\n}")
+        .await;
+    assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    let error = res.json::<Value>();
+    assert_eq!(error["code"], "invalid_template");
+    assert!(error["message"]
+        .as_str()
+        .unwrap()
+        .contains("Unknown node 'banner'"));
+    assert_eq!(error["errors"][0]["location"]["line"], 2);
+
+    let res = srv.get("/templates").await;
+    assert_eq!(
+        res.json::<Value>(),
+        json!([{"name": "staff", "versions": [1, 2]}])
+    );
+    let res = srv
+        .get("/templates/staff")
+        .add_query_param("version", 1)
+        .await;
+    assert_eq!(res.headers().get("x-template-version").unwrap(), "1");
+    assert_eq!(res.text(), template);
+
+    let payload = json!({
+        "report_data": std::fs::read_to_string("../../../data/report-data.json").expect("Failed to read file"),
+        "output_format": "html"
+    });
+    let res = srv.post("/templates/staff/render").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::OK);
+    assert!(res.text().contains("ABCDFG Ltd Staff List"));
+    let mut payload = payload;
+    payload["version"] = json!(1);
+    let res = srv.post("/templates/staff/render").json(&payload).await;
+    assert!(res.text().contains("ABCDFG Ltd Employee Report"));
+
+    let res = srv
+        .delete("/templates/staff")
+        .add_query_param("version", 2)
+        .await;
+    assert_eq!(res.status_code(), StatusCode::NO_CONTENT);
+    let res = srv.get("/templates/staff").await;
+    assert_eq!(res.headers().get("x-template-version").unwrap(), "1");
+    // The number of a deleted version is not given to other content.
+    let res = srv.put("/templates/staff").text(template.clone()).await;
+    assert_eq!(res.json::<Value>(), json!({"name": "staff", "version": 3}));
+    let res = srv
+        .get("/templates/staff")
+        .add_query_param("version", 2)
+        .await;
+    assert_eq!(res.status_code(), StatusCode::NOT_FOUND);
+    srv.delete("/templates/staff").await;
+    let res = srv.get("/templates/staff").await;
+    assert_eq!(res.status_code(), StatusCode::NOT_FOUND);
+    let res = srv.get("/templates").await;
+    assert_eq!(res.json::<Value>(), json!([]));
+    let res = srv.post("/templates/staff").text(template.clone()).await;
+    assert_eq!(res.status_code(), StatusCode::CREATED);
+    assert_eq!(res.json::<Value>(), json!({"name": "staff", "version": 4}));
+    let res = srv.get("/templates/..%2Fetc").await;
+    assert_eq!(res.status_code(), StatusCode::BAD_REQUEST);
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+async fn wait_for_job(srv: &TestServer, location: &str) -> Value {
+    for _ in 0..600 {
+        let job = srv.get(location).await.json::<Value>();
+        if job["status"] == "done" || job["status"] == "failed" {
+            return job;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("Job {} did not finish", location);
+}
+
+#[tokio::test]
+async fn test_jobs() {
+    let srv = TestServer::new(router()).unwrap();
+    let payload = json!({
+        "report_template": std::fs::read_to_string("../../../data/report-template.kdl")
+            .expect("Failed to read file")
+            .replace("image src=\"../../../logo.png\" width=100 height=100", ""),
+        "report_data": std::fs::read_to_string("../../../data/report-data.json").expect("Failed to read file"),
+        "output_format": "html",
+        "filename": "employees"
+    });
+
+    let res = srv.post("/jobs").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::ACCEPTED);
+    let location = res
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let job = res.json::<Value>();
+    assert_eq!(location, format!("/jobs/{}", job["id"].as_str().unwrap()));
+    assert_eq!(job["output_format"], "html");
+
+    let job = wait_for_job(&srv, &location).await;
+    assert_eq!(job["status"], "done");
+    assert!(job["expires_in"].as_u64().unwrap() > 0);
+    let res = srv.get(&format!("{}/result", location)).await;
+    assert_eq!(res.status_code(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"employees.html\""
+    );
+    assert!(res.text().contains("ABCDFG Ltd Employee Report"));
+
+    let res = srv.delete(&location).await;
+    assert_eq!(res.status_code(), StatusCode::NO_CONTENT);
+    let res = srv.get(&location).await;
+    assert_eq!(res.status_code(), StatusCode::NOT_FOUND);
+
+    let mut payload = payload;
+    payload["report_data"] = json!("{\"rows\": [");
+    let res = srv.post("/jobs").json(&payload).await;
+    let location = res
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let job = wait_for_job(&srv, &location).await;
+    assert_eq!(job["status"], "failed");
+    assert!(job["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("JSON parse error"));
+    let res = srv.get(&format!("{}/result", location)).await;
+    assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(res.json::<Value>()["code"], "data_parse_error");
+
+    payload["output_format"] = json!("exe");
+    let res = srv.post("/jobs").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::BAD_REQUEST);
+}
+
+/// Blocks rendering of a template's images until released.
+struct BlockingResolver(std::sync::Mutex<std::sync::mpsc::Receiver<()>>);
+
+impl ResourceResolver for BlockingResolver {
+    fn resolve(&self, _name: &str) -> Result<Option<Bytes>, ReportError> {
+        self.0.lock().unwrap().recv().ok();
+        Ok(None)
+    }
+}
+
+#[tokio::test]
+async fn test_jobs_queue_limit_and_ttl() {
+    let (release, blocked) = std::sync::mpsc::channel();
+    let jobs = JobQueue::new(JobsConfig {
+        workers: 1,
+        queue_limit: 1,
+        ttl: std::time::Duration::ZERO,
+    });
+    let srv = TestServer::new(router_with_options(
+        Arc::new(BlockingResolver(std::sync::Mutex::new(blocked))),
+        None,
+        Arc::new(jobs),
+    ))
+    .unwrap();
+    let payload = json!({
+        "report_template": "template {\n    title {\n        image src=\"logo.png\" width=10 height=10\n    }\n}",
+        "report_data": "{\"rows\": []}",
+        "output_format": "text"
+    });
+
+    let res = srv.post("/jobs").json(&payload).await;
+    let location = res
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let res = srv.post("/jobs").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    release.send(()).unwrap();
+
+    // Finished jobs expire at once with a zero TTL.
+    for _ in 0..600 {
+        if srv.get(&location).await.status_code() == StatusCode::NOT_FOUND {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(
+        srv.get(&location).await.status_code(),
+        StatusCode::NOT_FOUND
+    );
+    drop(release);
+    let res = srv.post("/jobs").json(&payload).await;
+    assert_eq!(res.status_code(), StatusCode::ACCEPTED);
+}